@@ -1,98 +1,394 @@
 use std::collections::BTreeMap;
-use crate::{naive_double::NaiveDouble, bytecode::Builtin};
+
+use num_bigint::{BigInt, BigUint, Sign};
+
+use crate::{naive_double::NaiveDouble, bytecode::Builtin, parse2::is_simple_label};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Var ( pub String, pub usize );  // label, index
 
+// Character range of an expression in its source file.
+pub type Span = std::ops::Range<usize>;
+
+// An expression together with the source range it was parsed from.
+// Spans are ignored when comparing nodes.
+#[derive(Debug, Clone)]
 pub struct Node {
-    expr: Expr,
-    span: usize,
+    pub expr: Expr,
+    pub span: Span,
+}
+
+impl Node {
+    pub fn new(expr: Expr, span: Span) -> Self {
+        Self { expr, span }
+    }
+}
+
+impl From<Expr> for Node {
+    fn from(expr: Expr) -> Self {
+        Self { expr, span: 0..0 }
+    }
+}
+
+impl std::ops::Deref for Node {
+    type Target = Expr;
+    fn deref(&self) -> &Expr {
+        &self.expr
+    }
+}
+
+impl std::ops::DerefMut for Node {
+    fn deref_mut(&mut self) -> &mut Expr {
+        &mut self.expr
+    }
+}
+
+impl PartialEq for Node {
+    fn eq(&self, other: &Self) -> bool {
+        self.expr == other.expr
+    }
+}
+
+impl Eq for Node {}
+
+impl std::fmt::Display for Node {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.expr.fmt(f)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Expr {
 
     // Some
-    Some(Box<Expr>),
+    Some(Box<Node>),
 
-    Text(Vec<(String, Option<Expr>)>),
+    Text(Vec<(String, Option<Node>)>),
     TextLit(String),
     BoolLit(bool),
-    NaturalLit(u64),
-    IntegerLit(i64),
+    NaturalLit(BigUint),
+    IntegerLit(BigInt),
     DoubleLit(NaiveDouble),
-    RecordLit(Vec<(String, Expr)>),
+    BytesLit(Vec<u8>),
+    DateLit(u16, u8, u8),           // year, month, day
+    TimeLit(u8, u8, u8, String),    // hour, minute, second, fractional digits of the second
+    TimeZoneLit(i16),               // offset in minutes
+    RecordLit(Vec<(String, Node)>),
     Builtin(Builtin),
     // let x : t = r in e
-    LetIn(Vec<(String, Option<Expr>, Expr)>, Box<Expr>),
-    Let(String, Box<Option<Expr>>, Box<Expr>, Box<Expr>),
+    LetIn(Vec<(String, Option<Node>, Node)>, Box<Node>),
+    Let(String, Box<Option<Node>>, Box<Node>, Box<Node>),
     // Record
-    RecordType(BTreeMap<String, Expr>),
-    Record(BTreeMap<String, Expr>),
+    RecordType(BTreeMap<String, Node>),
+    Record(BTreeMap<String, Node>),
     // List
-    ListLit(Vec<Expr>),
-    ListType(Box<Expr>),
+    ListLit(Vec<Node>),
+    // [] : T
+    EmptyListLit(Box<Node>),
     // Union
-    UnionType(BTreeMap<String, Option<Expr>>),
-    // UnionItem
-    // union type, name of variant, Literal
-    UnionItem(BTreeMap<String, Option<Expr>>, String, Option<Box<Expr>>),
+    UnionType(BTreeMap<String, Option<Node>>),
 
     Var(Var),
     // \(x : A) -> b
-    Select(Box<Expr>, String),
-    Lambda(String, Box<Expr>, Box<Expr>),  // arg-name, arg-type, expr
-    FnType(Box<Expr>, Box<Expr>),
-    Application(Vec<Expr>),
+    Select(Box<Node>, String),
+    // r.{ a, b }
+    Project(Box<Node>, Vec<String>),
+    // r.(T)
+    ProjectType(Box<Node>, Box<Node>),
+    Lambda(String, Box<Node>, Box<Node>),  // arg-name, arg-type, expr
+    // forall (x : A) -> B, x is "_" for A -> B
+    FnType(String, Box<Node>, Box<Node>),
+    Application(Vec<Node>),
+    // merge handlers union : T
+    Merge(Box<Node>, Box<Node>, Option<Box<Node>>),
+    ToMap(Box<Node>, Option<Box<Node>>),
+    ShowConstructor(Box<Node>),
 
     // Operations
     Op(Op),
-    Plus(Box<Expr>, Box<Expr>),
-    TextAppend(Box<Expr>, Box<Expr>),
-    ListAppend(Box<Expr>, Box<Expr>),
-    Equal(Box<Expr>, Box<Expr>),
-    NotEqual(Box<Expr>, Box<Expr>),
-    And(Box<Expr>, Box<Expr>),
-    Or(Box<Expr>, Box<Expr>),
-    Combine(Box<Expr>, Box<Expr>),
-    Prefer(Box<Expr>, Box<Expr>),
+    Plus(Box<Node>, Box<Node>),
+    TextAppend(Box<Node>, Box<Node>),
+    ListAppend(Box<Node>, Box<Node>),
+    Equal(Box<Node>, Box<Node>),
+    NotEqual(Box<Node>, Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Combine(Box<Node>, Box<Node>),
+    Prefer(Box<Node>, Box<Node>),
+    With(Box<Node>, Vec<PathComponent>, Box<Node>),
+    Completion(Box<Node>, Box<Node>),   // schema T and record r of `T::r`
 
 
     // x : t
-    IfThenElse(Box<Expr>, Box<Expr>, Box<Expr>),
-    Annot(Box<Expr>, Box<Expr>),
+    IfThenElse(Box<Node>, Box<Node>, Box<Node>),
+    Annot(Box<Node>, Box<Node>),
     // assert : x
-    Assert(Box<Expr>),
-    Import(Import),
+    Assert(Box<Node>),
+    Import(Import, ImportMode),
+    // placeholder for a part of the source that could not be parsed
+    Error,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Import {
     Local(String),
-    Remote(String),
+    // url and the optional `using` expression with request headers
+    Remote(String, Option<Box<Node>>),
     Env(String),
 }
 
+// A step of the path updated by `e with a.?.b = v`: a record field or the content of an Optional.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathComponent {
+    Label(String),
+    DescendOptional,
+}
+
+impl std::fmt::Display for PathComponent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PathComponent::Label(k) => write!(f, "{}", Label(k)),
+            PathComponent::DescendOptional => write!(f, "?"),
+        }
+    }
+}
+
+// What an import evaluates to: the Dhall expression in the imported file, its raw contents,
+// or where it would be read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ImportMode {
+    Code,
+    Text,
+    Bytes,
+    Location,
+}
+
+impl std::fmt::Display for Import {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Import::Local(path) => write!(f, "{path}"),
+            Import::Remote(url, None) => write!(f, "{url}"),
+            Import::Remote(url, Some(headers)) => write!(f, "{url} using {}", Atom(headers)),
+            Import::Env(var) if is_env_name(var) => write!(f, "env:{var}"),
+            Import::Env(var) => {
+                write!(f, "env:\"")?;
+                for c in var.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\u{7}' => write!(f, "\\a")?,
+                        '\u{8}' => write!(f, "\\b")?,
+                        '\u{c}' => write!(f, "\\f")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\r' => write!(f, "\\r")?,
+                        '\t' => write!(f, "\\t")?,
+                        '\u{b}' => write!(f, "\\v")?,
+                        c => write!(f, "{c}")?,
+                    }
+                }
+                write!(f, "\"")
+            },
+        }
+    }
+}
+
+// Variable names that can be imported without quotes, as in `env:HOME`.
+pub fn is_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Op {
-    App(Vec<Expr>),
-    Equivalent(Box<Expr>, Box<Expr>),
-    ImportAlt(Box<Expr>, Box<Expr>),
-    Or(Box<Expr>, Box<Expr>),
-    Plus(Box<Expr>, Box<Expr>),
-    TextAppend(Box<Expr>, Box<Expr>),
-    ListAppend(Box<Expr>, Box<Expr>),
-    And(Box<Expr>, Box<Expr>),
-    Combine(Box<Expr>, Box<Expr>),
-    Prefer(Box<Expr>, Box<Expr>),
-    CombineTypes(Box<Expr>, Box<Expr>),
-    Times(Box<Expr>, Box<Expr>),
-    Equal(Box<Expr>, Box<Expr>),
-    NotEqual(Box<Expr>, Box<Expr>),
-}
-
-
-
-pub trait Visitor<T> {
-    fn visit_expr(&mut self, expr: &Expr) -> T;
+    Equivalent(Box<Node>, Box<Node>),
+    ImportAlt(Box<Node>, Box<Node>),
+    CombineTypes(Box<Node>, Box<Node>),
+    Times(Box<Node>, Box<Node>),
+}
+
+
+// Escape a string so that it can be printed inside a double quoted Dhall text literal.
+pub fn escape_text(s: &str) -> String {
+    let mut r = String::new();
+    for c in s.chars() {
+        match c {
+            '"' => r.push_str("\\\""),
+            '$' => r.push_str("\\u0024"),
+            '\\' => r.push_str("\\\\"),
+            '\u{8}' => r.push_str("\\b"),
+            '\u{c}' => r.push_str("\\f"),
+            '\n' => r.push_str("\\n"),
+            '\r' => r.push_str("\\r"),
+            '\t' => r.push_str("\\t"),
+            c if (c as u32) < 0x20 => r.push_str(&format!("\\u{:04x}", c as u32)),
+            c => r.push(c),
+        }
+    }
+    r
+}
+
+// Wrapper to print labels in backticks unless they are simple.
+struct Label<'a>(&'a str);
+
+impl std::fmt::Display for Label<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if is_simple_label(self.0) { write!(f, "{}", self.0) } else { write!(f, "`{}`", self.0) }
+    }
+}
+
+// Wrapper to print sub-expressions in parentheses unless they are atomic.
+struct Atom<'a>(&'a Expr);
+
+impl std::fmt::Display for Atom<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Expr::Text(_) | Expr::TextLit(_) | Expr::BoolLit(_) | Expr::NaturalLit(_)
+            | Expr::IntegerLit(_) | Expr::DoubleLit(_) | Expr::BytesLit(_) | Expr::DateLit(..) | Expr::TimeLit(..)
+            | Expr::TimeZoneLit(_) | Expr::RecordLit(_) | Expr::Builtin(_)
+            | Expr::RecordType(_) | Expr::Record(_) | Expr::ListLit(_) | Expr::UnionType(_)
+            | Expr::Var(_) | Expr::Select(_, _) | Expr::Project(_, _) | Expr::ProjectType(_, _) | Expr::Completion(_, _)
+            | Expr::Import(..) => write!(f, "{}", self.0),
+            _ => write!(f, "({})", self.0),
+        }
+    }
+}
+
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn binop(f: &mut std::fmt::Formatter<'_>, l: &Expr, op: &str, r: &Expr) -> std::fmt::Result {
+            write!(f, "{} {op} {}", Atom(l), Atom(r))
+        }
+        match self {
+            Expr::Some(e) => write!(f, "Some {}", Atom(e)),
+            Expr::Text(chunks) => {
+                write!(f, "\"")?;
+                for (s, e) in chunks {
+                    write!(f, "{}", escape_text(s))?;
+                    if let Some(e) = e {
+                        write!(f, "${{{e}}}")?;
+                    }
+                }
+                write!(f, "\"")
+            },
+            Expr::TextLit(s) => write!(f, "\"{}\"", escape_text(s)),
+            Expr::BoolLit(b) => write!(f, "{}", if *b { "True" } else { "False" }),
+            Expr::NaturalLit(n) => write!(f, "{n}"),
+            Expr::IntegerLit(i) => if i.sign() == Sign::Minus { write!(f, "{i}") } else { write!(f, "+{i}") },
+            Expr::DoubleLit(d) => write!(f, "{d}"),
+            Expr::BytesLit(bytes) => {
+                write!(f, "0x\"")?;
+                for b in bytes {
+                    write!(f, "{b:02X}")?;
+                }
+                write!(f, "\"")
+            },
+            Expr::DateLit(y, m, d) => write!(f, "{y:04}-{m:02}-{d:02}"),
+            Expr::TimeLit(h, m, s, frac) => {
+                write!(f, "{h:02}:{m:02}:{s:02}")?;
+                if !frac.is_empty() { write!(f, ".{frac}")?; }
+                Ok(())
+            },
+            Expr::TimeZoneLit(offset) => {
+                let sign = if *offset < 0 { '-' } else { '+' };
+                write!(f, "{sign}{:02}:{:02}", offset.abs() / 60, offset.abs() % 60)
+            },
+            Expr::RecordLit(items) => {
+                if items.is_empty() { return write!(f, "{{=}}") }
+                let items: Vec<String> = items.iter().map(|(k, v)| format!("{} = {v}", Label(k))).collect();
+                write!(f, "{{ {} }}", items.join(", "))
+            },
+            Expr::Record(map) => {
+                if map.is_empty() { return write!(f, "{{=}}") }
+                let items: Vec<String> = map.iter().map(|(k, v)| format!("{} = {v}", Label(k))).collect();
+                write!(f, "{{ {} }}", items.join(", "))
+            },
+            Expr::RecordType(map) => {
+                if map.is_empty() { return write!(f, "{{}}") }
+                let items: Vec<String> = map.iter().map(|(k, v)| format!("{} : {v}", Label(k))).collect();
+                write!(f, "{{ {} }}", items.join(", "))
+            },
+            Expr::UnionType(map) => {
+                if map.is_empty() { return write!(f, "<>") }
+                let items: Vec<String> = map.iter().map(|(k, v)| match v {
+                    Some(t) => format!("{} : {t}", Label(k)),
+                    None => Label(k).to_string(),
+                }).collect();
+                write!(f, "< {} >", items.join(" | "))
+            },
+            Expr::Builtin(b) => write!(f, "{b}"),
+            Expr::LetIn(bindings, e) => {
+                for (name, t, v) in bindings {
+                    match t {
+                        Some(t) => write!(f, "let {} : {t} = {v} ", Label(name))?,
+                        None => write!(f, "let {} = {v} ", Label(name))?,
+                    }
+                }
+                write!(f, "in {e}")
+            },
+            Expr::Let(name, t, v, e) => match &**t {
+                Some(t) => write!(f, "let {} : {t} = {v} in {e}", Label(name)),
+                None => write!(f, "let {} = {v} in {e}", Label(name)),
+            },
+            Expr::ListLit(items) => {
+                let items: Vec<String> = items.iter().map(|e| e.to_string()).collect();
+                write!(f, "[ {} ]", items.join(", "))
+            },
+            Expr::EmptyListLit(t) => write!(f, "[] : {t}"),
+            Expr::Var(Var(name, idx)) => if *idx == 0 { write!(f, "{}", Label(name)) } else { write!(f, "{}@{idx}", Label(name)) },
+            Expr::Select(e, name) => write!(f, "{}.{}", Atom(e), Label(name)),
+            Expr::Project(e, labels) => {
+                let labels: Vec<String> = labels.iter().map(|k| Label(k).to_string()).collect();
+                write!(f, "{}.{{ {} }}", Atom(e), labels.join(", "))
+            },
+            Expr::ProjectType(e, t) => write!(f, "{}.({t})", Atom(e)),
+            Expr::Lambda(name, t, e) => write!(f, "λ({} : {t}) → {e}", Label(name)),
+            Expr::FnType(name, t, e) => if name == "_" {
+                write!(f, "{} → {e}", Atom(t))
+            } else {
+                write!(f, "∀({} : {t}) → {e}", Label(name))
+            },
+            Expr::Application(vec) => {
+                let items: Vec<String> = vec.iter().map(|e| Atom(e).to_string()).collect();
+                write!(f, "{}", items.join(" "))
+            },
+            Expr::Merge(h, u, t) => {
+                write!(f, "merge {} {}", Atom(h), Atom(u))?;
+                if let Some(t) = t { write!(f, " : {t}")?; }
+                Ok(())
+            },
+            Expr::ShowConstructor(e) => write!(f, "showConstructor {}", Atom(e)),
+            Expr::ToMap(e, t) => {
+                write!(f, "toMap {}", Atom(e))?;
+                if let Some(t) = t { write!(f, " : {t}")?; }
+                Ok(())
+            },
+            Expr::Plus(l, r) => binop(f, l, "+", r),
+            Expr::TextAppend(l, r) => binop(f, l, "++", r),
+            Expr::ListAppend(l, r) => binop(f, l, "#", r),
+            Expr::Equal(l, r) => binop(f, l, "==", r),
+            Expr::NotEqual(l, r) => binop(f, l, "!=", r),
+            Expr::And(l, r) => binop(f, l, "&&", r),
+            Expr::Or(l, r) => binop(f, l, "||", r),
+            Expr::Combine(l, r) => binop(f, l, "∧", r),
+            Expr::Prefer(l, r) => binop(f, l, "⫽", r),
+            Expr::Completion(t, r) => write!(f, "{}::{}", Atom(t), Atom(r)),
+            Expr::With(e, path, v) => {
+                let path: Vec<String> = path.iter().map(|c| c.to_string()).collect();
+                write!(f, "{} with {} = {}", Atom(e), path.join("."), Atom(v))
+            },
+            Expr::Op(Op::CombineTypes(l, r)) => binop(f, l, "⩓", r),
+            Expr::Op(Op::Times(l, r)) => binop(f, l, "*", r),
+            Expr::Op(Op::Equivalent(l, r)) => binop(f, l, "≡", r),
+            Expr::Op(Op::ImportAlt(l, r)) => binop(f, l, "?", r),
+            Expr::IfThenElse(c, t, e) => write!(f, "if {c} then {t} else {e}"),
+            Expr::Annot(e, t) => write!(f, "{} : {t}", Atom(e)),
+            Expr::Assert(t) => write!(f, "assert : {t}"),
+            Expr::Import(import, ImportMode::Code) => write!(f, "{import}"),
+            Expr::Import(import, ImportMode::Text) => write!(f, "{import} as Text"),
+            Expr::Import(import, ImportMode::Bytes) => write!(f, "{import} as Bytes"),
+            Expr::Import(import, ImportMode::Location) => write!(f, "{import} as Location"),
+            Expr::Error => write!(f, "<error>"),
+        }
+    }
 }
@@ -1,23 +1,30 @@
 
-use std::{rc::Rc, cell::RefCell, collections::BTreeMap};
+use std::{rc::Rc, cell::RefCell, collections::{BTreeMap, BTreeSet}, path::PathBuf};
 
-use crate::error::{RuntimeError, CompileError};
+use num_bigint::{BigInt, BigUint};
+
+use crate::ast::{Expr, PathComponent, Span, escape_text};
+use crate::parse2::is_simple_label;
+use crate::naive_double::NaiveDouble;
+use crate::error::{RuntimeError, CompileError, Location};
 
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct Chunk {
     pub code: Vec<Op>,
     pub constants: Vec<Value>,
-    pub spans: Vec<usize>,
+    pub spans: Vec<Span>,   // source range of each op in `file`
+    pub file: Rc<PathBuf>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Op {
-    Import(usize),
     Builtin(Builtin),
     Pop,
     PopBeneath,
     Call(usize), // arg_cnt
     Return,
+    Jump(usize),        // offset forward from the next op
+    JumpIfFalse(usize), // jumps if the Bool on top of the stack is False, without popping it
     Closure(usize),
     Upval(UpvalueLoc), // separate from Closure to not inflate Op too much
     CloseUpvalueBeneath,
@@ -27,27 +34,46 @@ pub enum Op {
     GetUpval(usize),
     CreateRecord(usize),
     CreateList(usize),
+    Select(usize),  // constant index of the label
+    Project,        // record and record type (or field names) on the stack
+    Merge,
+    ToMap,
+    ShowConstructor,
     Add,
+    Mul,
     TextAppend,
     ListAppend,
     Equal,
     NotEqual,
-    And,
-    Or,
     Combine,
     Prefer,
+    With(usize),    // constant index of the path, record and new value on the stack
+    Complete,       // record completion, schema `{ Type, default }` and record on the stack
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Value {
     Builtin(Builtin),
-    Natural(u64),
-    Integer(i64),
+    PartialBuiltin(Builtin, Vec<Value>),    // builtin applied to fewer arguments than it takes
+    Natural(BigUint),
+    Successor(BigUint),     // an unknown Natural plus an offset, only used inside Vm::increment
+    Integer(BigInt),
+    Double(NaiveDouble),
+    Date(u16, u8, u8),
+    Time(u8, u8, u8, String),
+    TimeZone(i16),
     String(String),
+    Bytes(Vec<u8>),
     Bool(bool),
     Option(Option<Box<Value>>),
     Record(BTreeMap<String, Value>),
+    RecordType(BTreeSet<String>),   // field names, used to project records
+    Path(Vec<PathComponent>),   // path updated by a with expression
     List(Vec<Value>),
+    UnionType(BTreeMap<String, bool>),  // alternative name, whether it takes an argument
+    Union(String, Option<Box<Value>>),
+    Constructor(String),    // constructor of a union alternative, applied like a function
+    Type,   // types are erased at runtime, this is passed where a function expects a type argument
     Function(Function),
     Closure(Closure),
 }
@@ -58,25 +84,20 @@ impl Value {
             Ok(val)
         } else { Err(RuntimeError::Basic(format!("Expected String, got {self:?} instead."))) }
     }
-    pub fn assume_natural(self) -> Result<u64, RuntimeError> {
+    pub fn assume_natural(self) -> Result<BigUint, RuntimeError> {
         if let Value::Natural(val) = self {
             Ok(val)
         } else { Err(RuntimeError::Basic(format!("Expected Natural, got {self:?} instead."))) }
     }
-    pub fn assume_integer(self) -> Result<i64, RuntimeError> {
+    pub fn assume_integer(self) -> Result<BigInt, RuntimeError> {
         if let Value::Integer(val) = self {
             Ok(val)
         } else { Err(RuntimeError::Basic(format!("Expected Integer, got {self:?} instead."))) }
     }
-    pub fn assume_builtin(self) -> Result<Builtin, RuntimeError> {
-        if let Value::Builtin(val) = self {
-            Ok(val)
-        } else { Err(RuntimeError::Basic(format!("Expected Builtin, got {self:?} instead."))) }
-    }
-    pub fn assume_bool(self) -> Result<bool, RuntimeError> {
-        if let Value::Bool(val) = self {
+    pub fn assume_double(self) -> Result<NaiveDouble, RuntimeError> {
+        if let Value::Double(val) = self {
             Ok(val)
-        } else { Err(RuntimeError::Basic(format!("Expected Bool, got {self:?} instead."))) }
+        } else { Err(RuntimeError::Basic(format!("Expected Double, got {self:?} instead."))) }
     }
     pub fn assume_list(self) -> Result<Vec<Value>, RuntimeError> {
         if let Value::List(val) = self {
@@ -88,10 +109,49 @@ impl Value {
             Ok(val)
         } else { Err(RuntimeError::Basic(format!("Expected Record, got {self:?} instead."))) }
     }
-    pub fn assume_function(self) -> Result<Function, RuntimeError> {
-        if let Value::Function(val) = self {
-            Ok(val)
-        } else { Err(RuntimeError::Basic(format!("Expected Function, got {self:?} instead."))) }
+}
+
+// Values are shown in Dhall syntax where possible. Types are erased at runtime, so empty
+// lists and union values are shown without their type.
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn join(f: &mut std::fmt::Formatter<'_>, items: impl Iterator<Item = String>, sep: &str) -> std::fmt::Result {
+            write!(f, "{}", items.collect::<Vec<_>>().join(sep))
+        }
+        let label = |k: &str| if is_simple_label(k) { k.to_string() } else { format!("`{k}`") };
+        match self {
+            Value::Builtin(b) => write!(f, "{b}"),
+            Value::Natural(n) => write!(f, "{n}"),
+            Value::Integer(i) => write!(f, "{}", Expr::IntegerLit(i.clone())),
+            Value::Double(d) => write!(f, "{d}"),
+            Value::Date(y, m, d) => write!(f, "{}", Expr::DateLit(*y, *m, *d)),
+            Value::Time(h, m, s, frac) => write!(f, "{}", Expr::TimeLit(*h, *m, *s, frac.clone())),
+            Value::TimeZone(offset) => write!(f, "{}", Expr::TimeZoneLit(*offset)),
+            Value::String(s) => write!(f, "\"{}\"", escape_text(s)),
+            Value::Bytes(bytes) => write!(f, "{}", Expr::BytesLit(bytes.clone())),
+            Value::Bool(b) => write!(f, "{}", if *b { "True" } else { "False" }),
+            Value::Option(Some(v)) => write!(f, "Some {v}"),
+            Value::Option(None) => write!(f, "None"),
+            Value::Record(map) if map.is_empty() => write!(f, "{{=}}"),
+            Value::Record(map) => {
+                write!(f, "{{ ")?;
+                join(f, map.iter().map(|(k, v)| format!("{} = {v}", label(k))), ", ")?;
+                write!(f, " }}")
+            },
+            Value::List(items) if items.is_empty() => write!(f, "[]"),
+            Value::List(items) => {
+                write!(f, "[ ")?;
+                join(f, items.iter().map(|v| v.to_string()), ", ")?;
+                write!(f, " ]")
+            },
+            Value::Union(k, Some(v)) => write!(f, "{} {v}", label(k)),
+            Value::Union(k, None) | Value::Constructor(k) => write!(f, "{}", label(k)),
+            Value::RecordType(_) | Value::UnionType(_) | Value::Type => write!(f, "<type>"),
+            Value::Path(path) => join(f, path.iter().map(|c| c.to_string()), "."),
+            Value::PartialBuiltin(..) | Value::Function(_) | Value::Closure(_) => write!(f, "<function>"),
+            // only exists while Vm::increment probes a function and is never part of a result
+            Value::Successor(_) => write!(f, "{}", RuntimeError::InternalBug("Showing the unknown Natural of a fold.".to_string())),
+        }
     }
 }
 
@@ -149,28 +209,29 @@ impl Chunk {
         Chunk::default()
     }
 
-    pub fn disassemble(&self, name: &str) {
-        // todo, add line information
-        println!("== {} ==", name);
-        for (offset, op) in self.code.iter().enumerate() {
-            println!("{:04}    {}", offset, op.to_string());
-        }
-    }
-
-    pub fn push_op(&mut self, op: Op, span: usize) {
+    pub fn push_op(&mut self, op: Op, span: Span) {
         self.code.push(op);
         self.spans.push(span);
     }
-    pub fn push_op_below(&mut self, depth: usize, op: Op, span: usize) {
-        let len = self.code.len();
-        self.code.insert(len - depth, op);
-        self.spans.insert(len - depth, span);
+
+    // Points the jump at `idx` to the next op to be pushed.
+    pub fn patch_jump(&mut self, idx: usize) {
+        let offset = self.code.len() - idx - 1;
+        match &mut self.code[idx] {
+            Op::Jump(o) | Op::JumpIfFalse(o) => *o = offset,
+            op => panic!("Cannot patch {op:?} as a jump."),
+        }
     }
 
     pub fn peek_op(&self) -> &Op {
         self.code.last().unwrap()
     }
 
+    pub fn location(&self, offset: usize) -> Option<Location> {
+        let span = self.spans.get(offset)?;
+        Some(Location::new(self.file.to_path_buf(), span.clone()))
+    }
+
 
     pub fn get_constant(&self, idx: usize) -> Result<Value, RuntimeError> {
         if let Some(val) = self.constants.get(idx) {
@@ -188,16 +249,6 @@ impl Chunk {
 
 
 
-impl Op {
-    pub fn to_string(&self) -> String {
-        match self {
-            Op::Return => "OP_RETURN".to_string(),
-            Op::Constant(c_idx) => format!("OP_CONSTANT  {}", c_idx),
-            _ => todo!("{:?}", self)
-        }
-    }
-}
-
 impl Function {
     pub fn new() -> Self {
         Self { arity: 0, chunk: Chunk::new() }
@@ -205,7 +256,7 @@ impl Function {
 }
 
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Builtin {
     NaturalFold,
     NaturalBuild,
@@ -229,6 +280,9 @@ pub enum Builtin {
     ListReverse,
     TextShow,
     TextReplace,
+    DateShow,
+    TimeShow,
+    TimeZoneShow,
     Bool,
     True,
     False,
@@ -238,7 +292,11 @@ pub enum Builtin {
     Integer,
     Double,
     Text,
+    Bytes,
     List,
+    Date,
+    Time,
+    TimeZone,
     Type,
     Kind,
     Sort,
@@ -247,7 +305,7 @@ pub enum Builtin {
 
 pub fn builtin_fn_args(builtin: &Builtin) -> Result<usize, CompileError> {
     match builtin {
-        Builtin::NaturalFold => Ok(1),
+        Builtin::NaturalFold => Ok(4),
         Builtin::NaturalBuild => Ok(1),
         Builtin::NaturalIsZero => Ok(1),
         Builtin::NaturalEven => Ok(1),
@@ -260,16 +318,70 @@ pub fn builtin_fn_args(builtin: &Builtin) -> Result<usize, CompileError> {
         Builtin::IntegerNegate => Ok(1),
         Builtin::IntegerClamp => Ok(1),
         Builtin::DoubleShow => Ok(1),
-        Builtin::ListBuild => Ok(1),
-        Builtin::ListFold => Ok(1),
-        Builtin::ListLength => Ok(1),
-        Builtin::ListHead => Ok(1),
-        Builtin::ListLast => Ok(1),
-        Builtin::ListIndexed => Ok(1),
-        Builtin::ListReverse => Ok(1),
+        Builtin::ListBuild => Ok(2),
+        Builtin::ListFold => Ok(5),
+        Builtin::ListLength => Ok(2),
+        Builtin::ListHead => Ok(2),
+        Builtin::ListLast => Ok(2),
+        Builtin::ListIndexed => Ok(2),
+        Builtin::ListReverse => Ok(2),
         Builtin::TextShow => Ok(1),
         Builtin::TextReplace => Ok(3),
+        Builtin::DateShow => Ok(1),
+        Builtin::TimeShow => Ok(1),
+        Builtin::TimeZoneShow => Ok(1),
         Builtin::Some => Ok(1),
+        Builtin::None => Ok(1),
         _ => Err(CompileError::InternalBug("Only builtin functions may have arguments.".to_string())),
     }
-}
\ No newline at end of file
+}
+impl std::fmt::Display for Builtin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Builtin::NaturalFold => "Natural/fold",
+            Builtin::NaturalBuild => "Natural/build",
+            Builtin::NaturalIsZero => "Natural/isZero",
+            Builtin::NaturalEven => "Natural/even",
+            Builtin::NaturalOdd => "Natural/odd",
+            Builtin::NaturalToInteger => "Natural/toInteger",
+            Builtin::NaturalShow => "Natural/show",
+            Builtin::IntegerToDouble => "Integer/toDouble",
+            Builtin::IntegerShow => "Integer/show",
+            Builtin::IntegerNegate => "Integer/negate",
+            Builtin::IntegerClamp => "Integer/clamp",
+            Builtin::NaturalSubtract => "Natural/subtract",
+            Builtin::DoubleShow => "Double/show",
+            Builtin::ListBuild => "List/build",
+            Builtin::ListFold => "List/fold",
+            Builtin::ListLength => "List/length",
+            Builtin::ListHead => "List/head",
+            Builtin::ListLast => "List/last",
+            Builtin::ListIndexed => "List/indexed",
+            Builtin::ListReverse => "List/reverse",
+            Builtin::TextShow => "Text/show",
+            Builtin::TextReplace => "Text/replace",
+            Builtin::DateShow => "Date/show",
+            Builtin::TimeShow => "Time/show",
+            Builtin::TimeZoneShow => "TimeZone/show",
+            Builtin::Bool => "Bool",
+            Builtin::True => "True",
+            Builtin::False => "False",
+            Builtin::Optional => "Optional",
+            Builtin::None => "None",
+            Builtin::Natural => "Natural",
+            Builtin::Integer => "Integer",
+            Builtin::Double => "Double",
+            Builtin::Text => "Text",
+            Builtin::Bytes => "Bytes",
+            Builtin::Date => "Date",
+            Builtin::Time => "Time",
+            Builtin::TimeZone => "TimeZone",
+            Builtin::List => "List",
+            Builtin::Type => "Type",
+            Builtin::Kind => "Kind",
+            Builtin::Sort => "Sort",
+            Builtin::Some => "Some",
+        };
+        write!(f, "{name}")
+    }
+}
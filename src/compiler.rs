@@ -1,17 +1,19 @@
 
 use std::path::PathBuf;
+use std::rc::Rc;
 
-use crate::ast::{Expr, self, Var, Import};
+use crate::ast::{self, Expr, Node, Span};
 use crate::bytecode::{Op, Value, Function, UpvalueLoc, Builtin, builtin_fn_args};
-use crate::error::CompileError;
-use crate::{import2, vm};
+use crate::error::{CompileError, Location};
 
 
-pub fn compile(ast: &Expr, file: PathBuf) -> Result<Function, CompileError> {
+// Compiles an expression that was resolved and accepted by the typechecker. Anything that only
+// an ill-typed expression can lead to is reported as an internal bug.
+pub fn compile(ast: &Node, file: PathBuf) -> Result<Function, CompileError> {
     let mut compiler = Compiler::new(file);
     compiler.compile(ast)?;
     let mut function = compiler.get_function();
-    function.chunk.push_op(Op::Return, 0);
+    function.chunk.push_op(Op::Return, ast.span.clone());
     Ok(function)
 }
 
@@ -23,7 +25,7 @@ enum ResolvedVar {
 #[derive(Debug, Default)]
 struct Compiler {
     compilers: Vec<FunctionCompiler>,
-    file: PathBuf,
+    file: Rc<PathBuf>,
 }
 
 #[derive(Debug)]
@@ -39,12 +41,15 @@ struct Local {
     name: String,
     depth: usize,
     is_captured: bool,
+    span: Span,
 }
 
 impl FunctionCompiler {
-    pub fn new() -> Self {
+    pub fn new(file: Rc<PathBuf>) -> Self {
         let locals = vec![Local::default()];
-        Self { func: Function::new(), scope_depth: 0, locals, upvalues: Vec::new() }
+        let mut func = Function::new();
+        func.chunk.file = file;
+        Self { func, scope_depth: 0, locals, upvalues: Vec::new() }
     }
     // consume compiler and return generated code chunk
     pub fn get_function(self) -> Function {
@@ -55,7 +60,8 @@ impl FunctionCompiler {
 impl Compiler {
 
     pub fn new(file: PathBuf) -> Self {
-        Self { compilers: vec![FunctionCompiler::new()], file }
+        let file = Rc::new(file);
+        Self { compilers: vec![FunctionCompiler::new(file.clone())], file }
     }
 
     pub fn get_function(mut self) -> Function {
@@ -64,53 +70,50 @@ impl Compiler {
     }
 
     fn push_compiler(&mut self) {
-        self.compilers.push(FunctionCompiler::new());
+        self.compilers.push(FunctionCompiler::new(self.file.clone()));
     }
     fn pop_compiler(&mut self) -> FunctionCompiler {
         self.compilers.pop().unwrap()
     }
 
-    pub fn compile(&mut self, ast: &Expr) -> Result<(), CompileError> {
-        match ast {
-            Expr::Import(import) => {
-                match import {
-                    Import::Local(file) => {
-                        let mut file_dir = self.file.clone();
-                        file_dir.pop();
-                        let mut path = file_dir.join(file);
-                        println!("Joined: {path:?}");
-                        path = std::fs::canonicalize(path).unwrap();
-                        let path_string = path.to_string_lossy().to_string();
-                        let import_idx = if let Some(import_idx) = vm::get_import_index(&path_string) {
-                            println!("Getting value from stash (idx={import_idx}): {path_string}.");
-                            import_idx
-                        } else {
-                            // Dhall imports cannot close over values from importing contexts,
-                            // and thus can be precompiled and pre-executed and stored as value to
-                            // be pushed directly to the stack.
-                            let func = import2::import_file_local(&path)?;
-                            println!("Got function {:?}.", func.chunk);
-                            let val = vm::run_function(func, true).unwrap();
-                            let import_idx = vm::add_import_value(path_string, val.clone());
-                            println!("Saving value to stash (idx={import_idx}): {val:?}.");
-                            import_idx
-                        };
-                        self.emit(Op::Import(import_idx), 0);
-                    },
-                    _ => todo!("{import:?}"),
-                }
-            },
+    pub fn compile(&mut self, ast: &Node) -> Result<(), CompileError> {
+        let span = ast.span.clone();
+        match &ast.expr {
             Expr::NaturalLit(val) => {
-                let const_idx = self.add_constant(Value::Natural(*val));
-                self.emit(Op::Constant(const_idx), 0);
+                let const_idx = self.add_constant(Value::Natural(val.clone()));
+                self.emit(Op::Constant(const_idx), span.clone());
             },
             Expr::IntegerLit(val) => {
-                let const_idx = self.add_constant(Value::Integer(*val));
-                self.emit(Op::Constant(const_idx), 0);
+                let const_idx = self.add_constant(Value::Integer(val.clone()));
+                self.emit(Op::Constant(const_idx), span.clone());
+            },
+            Expr::DoubleLit(val) => {
+                let const_idx = self.add_constant(Value::Double(*val));
+                self.emit(Op::Constant(const_idx), span.clone());
+            },
+            Expr::BytesLit(bytes) => {
+                let const_idx = self.add_constant(Value::Bytes(bytes.clone()));
+                self.emit(Op::Constant(const_idx), span.clone());
+            },
+            Expr::DateLit(y, m, d) => {
+                let const_idx = self.add_constant(Value::Date(*y, *m, *d));
+                self.emit(Op::Constant(const_idx), span.clone());
+            },
+            Expr::TimeLit(h, m, s, frac) => {
+                let const_idx = self.add_constant(Value::Time(*h, *m, *s, frac.clone()));
+                self.emit(Op::Constant(const_idx), span.clone());
+            },
+            Expr::TimeZoneLit(offset) => {
+                let const_idx = self.add_constant(Value::TimeZone(*offset));
+                self.emit(Op::Constant(const_idx), span.clone());
             },
             Expr::BoolLit(val) => {
                 let const_idx = self.add_constant(Value::Bool(*val));
-                self.emit(Op::Constant(const_idx), 0);
+                self.emit(Op::Constant(const_idx), span.clone());
+            },
+            Expr::TextLit(s) => {
+                let const_idx = self.add_constant(Value::String(s.clone()));
+                self.emit(Op::Constant(const_idx), span.clone());
             },
             Expr::Text(vec) => {
                 let mut n_slices = 0;
@@ -118,158 +121,193 @@ impl Compiler {
                     // println!("{s}, {e:?}");
                     if !s.is_empty() {
                         let const_idx = self.add_constant(Value::String(s.clone()));
-                        self.emit(Op::Constant(const_idx), 0);
+                        self.emit(Op::Constant(const_idx), span.clone());
+                        self.push_temporary();
+                        n_slices += 1;
                     }
                     if let Some(e) = e {
                         self.compile(e)?;
+                        self.push_temporary();
                         n_slices += 1;
                     }
                 }
-                for _ in 0..n_slices {
-                    self.emit(Op::TextAppend, 0)
+                if n_slices == 0 {
+                    let const_idx = self.add_constant(Value::String(String::new()));
+                    self.emit(Op::Constant(const_idx), span.clone());
                 }
-            },
-            Expr::RecordLit(items) => {
-                self.begin_scope();
-                for (s, e) in items {
-                    self.compile(e)?;
-                    self.declare_variable(s.clone())?;
-                }
-                for (s, _) in items {
-                    let c = self.add_constant(Value::String(s.clone()));
-                    self.emit(Op::Constant(c), 0);
-                    self.compile(&Expr::Var(Var(s.clone(), 0)))?;
+                for _ in 1..n_slices {
+                    self.emit(Op::TextAppend, span.clone())
                 }
-                self.emit(Op::CreateRecord(items.len()), 0);
-                self.end_scope_with_result();
+                self.pop_temporaries(n_slices);
             },
+            Expr::RecordLit(items) => self.compile_record(items.iter().map(|(s, e)| (s, e)).collect(), span)?,
+            Expr::Record(map) => self.compile_record(map.iter().collect(), span)?,
             Expr::ListLit(items) => {
                 for e in items {
                     self.compile(e)?;
+                    self.push_temporary();
                 }
-                self.emit(Op::CreateList(items.len()), 0);
+                self.emit(Op::CreateList(items.len()), span.clone());
+                self.pop_temporaries(items.len());
+            },
+            Expr::EmptyListLit(_) => {
+                self.emit(Op::CreateList(0), span.clone());
+            },
+            Expr::UnionType(map) => {
+                // union types are kept at runtime to select their constructors
+                let alternatives = map.iter().map(|(k, v)| (k.clone(), v.is_some())).collect();
+                let const_idx = self.add_constant(Value::UnionType(alternatives));
+                self.emit(Op::Constant(const_idx), span.clone());
+            },
+            Expr::RecordType(map) => {
+                // record types are kept at runtime to project records
+                let const_idx = self.add_constant(Value::RecordType(map.keys().cloned().collect()));
+                self.emit(Op::Constant(const_idx), span.clone());
+            },
+            Expr::Project(e, labels) => {
+                self.compile(e)?;
+                self.push_temporary();
+                let const_idx = self.add_constant(Value::RecordType(labels.iter().cloned().collect()));
+                self.emit(Op::Constant(const_idx), span.clone());
+                self.emit(Op::Project, span.clone());
+                self.pop_temporaries(1);
+            },
+            Expr::ProjectType(e, t) => self.compile_binary_op(e, t, Op::Project, span)?,
+            Expr::Merge(h, u, _) => self.compile_binary_op(h, u, Op::Merge, span)?,
+            Expr::ShowConstructor(e) => {
+                self.compile(e)?;
+                self.emit(Op::ShowConstructor, span.clone());
+            },
+            Expr::ToMap(e, _) => {
+                self.compile(e)?;
+                self.emit(Op::ToMap, span.clone());
+            },
+            Expr::Select(e, k) => {
+                self.compile(e)?;
+                let const_idx = self.add_constant(Value::String(k.clone()));
+                self.emit(Op::Select(const_idx), span.clone());
             },
             Expr::LetIn(vec, sub) => {
                 self.begin_scope();
                 for (name, _, val) in vec {
-                    if matches!(val, &Expr::RecordType(_)) {
-                        // ignore type definitions
-                        continue;
-                    }
                     self.compile(val)?;
-                    self.declare_variable(name.clone())?;
+                    self.declare_variable(name.clone(), val.span.clone());
                 }
                 self.compile(sub)?;
-                self.end_scope_with_result();
+                self.end_scope_with_result(span.clone());
+            },
+            Expr::Let(name, _, val, sub) => {
+                self.begin_scope();
+                self.compile(val)?;
+                self.declare_variable(name.clone(), val.span.clone());
+                self.compile(sub)?;
+                self.end_scope_with_result(span.clone());
             },
             Expr::Lambda(arg_name, _, expr) => {
                 self.push_compiler();
                 self.function().arity = 1;  // lambdas always have one argument
-                self.declare_variable(arg_name.clone())?;  // Register arg_name to point to first slot of call frame
+                self.declare_variable(arg_name.clone(), span.clone());  // Register arg_name to point to first slot of call frame
                 self.compile(expr)?;
                 for (idx, local) in self.compiler().locals.clone().iter().enumerate() {
                     if local.is_captured {
-                        self.emit(Op::CloseUpvalue(idx), 0);
+                        self.emit(Op::CloseUpvalue(idx), span.clone());
                     }
                 }
-                self.emit(Op::Return, 0);
+                self.emit(Op::Return, span.clone());
                 let upvalues = self.compiler().upvalues.clone();  // inefficient
                 let func = self.pop_compiler().get_function();
                 let const_idx = self.add_constant(Value::Function(func));  // add function to constants
-                self.emit(Op::Closure(const_idx), 0);  // Refer to constant in bytecode
+                self.emit(Op::Closure(const_idx), span.clone());  // Refer to constant in bytecode
                 for upval in upvalues {
-                    self.emit(Op::Upval(upval), 0);
+                    self.emit(Op::Upval(upval), span.clone());
                 }
             },
             Expr::Application(vec) => {
                 // parser ensures length of vector is at least 2
-                println!("Compiling application: {vec:?}");
                 // let first = vec.len()-1;
                 // self.compile(&vec[first])?;
                 // for j in (0..first).rev() {
                 //     self.compile(&vec[j])?;
-                //     self.emit(Op::Call(1), 0);
+                //     self.emit(Op::Call(1), span.clone());
                 // }
 
                 let len = vec.len();
                 let mut j = 1;
                 self.compile(&vec[0])?;
-                while j < len {
-                    if let Op::Builtin(b) = self.peek_op() {
-                        // If function to be applied is to be a builtin, compile its arguments then Call(nargs)
-                        // Check that nargs does not exceed remaining items in call vector
-                        let n_args = builtin_fn_args(b)?;
-                        if n_args > len - j {
-                            Err(CompileError::Basic(format!("Less arguments then expected for builtin {b:?}: {} instead of {n_args}", j+1)))?
-                        }
-                        for _ in 0..n_args {
-                            self.compile(&vec[j])?;
-                            j += 1;
-                        }
-                        self.emit(Op::Call(n_args), 0);
-                    } else {
+                self.push_temporary();
+                if let (Op::Builtin(b), Expr::Builtin(_)) = (self.peek_op(), &vec[0].expr) {
+                    // If function to be applied is to be a builtin, compile its arguments then Call(nargs).
+                    // With fewer arguments the VM keeps the builtin partially applied.
+                    let n_args = builtin_fn_args(b)?.min(len - j);
+                    for _ in 0..n_args {
                         self.compile(&vec[j])?;
+                        self.push_temporary();
                         j += 1;
-                        self.emit(Op::Call(1), 0);
                     }
+                    self.emit(Op::Call(n_args), span.clone());
+                    self.pop_temporaries(n_args);
+                }
+                while j < len {
+                    self.compile(&vec[j])?;
+                    j += 1;
+                    self.emit(Op::Call(1), span.clone());
                 }
+                self.pop_temporaries(1);
             },
             Expr::Var(var) => {
-                // TODO: x@2
-                let var = self.resolve_variable(&var.0)?;
+                let var = self.resolve_variable(&var.0, var.1, span.clone())?;
                 match var {
-                    ResolvedVar::Local(idx) => self.emit(Op::GetVar(idx), 0),
-                    ResolvedVar::Upval(idx) => self.emit(Op::GetUpval(idx), 0),
+                    ResolvedVar::Local(idx) => self.emit(Op::GetVar(idx), span.clone()),
+                    ResolvedVar::Upval(idx) => self.emit(Op::GetUpval(idx), span.clone()),
                 }
             },
 
             // Operations
 
-            Expr::Plus(l, r) => {
-                self.compile(l)?;
-                self.compile(r)?;
-                self.emit(Op::Add, 0);
-            },
+            Expr::Plus(l, r) => self.compile_binary_op(l, r, Op::Add, span)?,
+            Expr::Op(ast::Op::Times(l, r)) => self.compile_binary_op(l, r, Op::Mul, span)?,
 
-            Expr::TextAppend(l, r) => {
-                self.compile(l)?;
-                self.compile(r)?;
-                self.emit(Op::TextAppend, 0);
-            },
-            Expr::ListAppend(l, r) => {
-                self.compile(l)?;
-                self.compile(r)?;
-                self.emit(Op::ListAppend, 0);
-            },
-            Expr::Equal(l, r) => {
-                self.compile(l)?;
-                self.compile(r)?;
-                self.emit(Op::Equal, 0);
-            },
-            Expr::NotEqual(l, r) => {
-                self.compile(l)?;
-                self.compile(r)?;
-                self.emit(Op::NotEqual, 0);
-            },
+            Expr::TextAppend(l, r) => self.compile_binary_op(l, r, Op::TextAppend, span)?,
+            Expr::ListAppend(l, r) => self.compile_binary_op(l, r, Op::ListAppend, span)?,
+            Expr::Equal(l, r) => self.compile_binary_op(l, r, Op::Equal, span)?,
+            Expr::NotEqual(l, r) => self.compile_binary_op(l, r, Op::NotEqual, span)?,
             Expr::And(l, r) => {
+                // l && r: r is only evaluated if l is True
                 self.compile(l)?;
+                let end = self.emit_jump(Op::JumpIfFalse(0), span.clone());
+                self.emit(Op::Pop, span.clone());
                 self.compile(r)?;
-                self.emit(Op::And, 0);
+                self.patch_jump(end);
             },
             Expr::Or(l, r) => {
+                // l || r: r is only evaluated if l is False
                 self.compile(l)?;
+                let else_jump = self.emit_jump(Op::JumpIfFalse(0), span.clone());
+                let end = self.emit_jump(Op::Jump(0), span.clone());
+                self.patch_jump(else_jump);
+                self.emit(Op::Pop, span.clone());
                 self.compile(r)?;
-                self.emit(Op::Or, 0);
+                self.patch_jump(end);
             },
-            Expr::Combine(l, r) => {
-                self.compile(l)?;
-                self.compile(r)?;
-                self.emit(Op::Combine, 0);
+            Expr::IfThenElse(c, t, e) => {
+                // only the taken branch is evaluated; the condition is popped in either branch
+                self.compile(c)?;
+                let else_jump = self.emit_jump(Op::JumpIfFalse(0), span.clone());
+                self.emit(Op::Pop, span.clone());
+                self.compile(t)?;
+                let end = self.emit_jump(Op::Jump(0), span.clone());
+                self.patch_jump(else_jump);
+                self.emit(Op::Pop, span.clone());
+                self.compile(e)?;
+                self.patch_jump(end);
             },
-            Expr::Prefer(l, r) => {
-                self.compile(l)?;
-                self.compile(r)?;
-                self.emit(Op::Prefer, 0);
+            Expr::Combine(l, r) => self.compile_binary_op(l, r, Op::Combine, span)?,
+            Expr::Prefer(l, r) => self.compile_binary_op(l, r, Op::Prefer, span)?,
+            // T::r is T.default // r, the VM checks that the result has the fields of T.Type
+            Expr::Completion(t, r) => self.compile_binary_op(t, r, Op::Complete, span)?,
+            Expr::With(e, path, v) => {
+                let const_idx = self.add_constant(Value::Path(path.clone()));
+                self.compile_binary_op(e, v, Op::With(const_idx), span)?
             },
 
 
@@ -298,15 +336,43 @@ impl Compiler {
                     | Builtin::ListReverse
                     | Builtin::TextShow
                     | Builtin::TextReplace
-                        => self.emit(Op::Builtin(b.clone()), 0),
-                    _ => todo!("{b:?}"),
+                    | Builtin::DateShow
+                    | Builtin::TimeShow
+                    | Builtin::TimeZoneShow
+                    | Builtin::None
+                    | Builtin::Some
+                        => self.emit(Op::Builtin(b.clone()), span.clone()),
+                    Builtin::Bool
+                    | Builtin::Optional
+                    | Builtin::Natural
+                    | Builtin::Integer
+                    | Builtin::Double
+                    | Builtin::Text
+                    | Builtin::Bytes
+                    | Builtin::List
+                    | Builtin::Date
+                    | Builtin::Time
+                    | Builtin::TimeZone
+                    | Builtin::Type
+                    | Builtin::Kind
+                    | Builtin::Sort
+                        => self.compile_type(span),
+                    Builtin::True | Builtin::False => {
+                        let const_idx = self.add_constant(Value::Bool(*b == Builtin::True));
+                        self.emit(Op::Constant(const_idx), span.clone());
+                    },
                 }
             },
+            Expr::FnType(..) | Expr::Op(ast::Op::Equivalent(..)) | Expr::Op(ast::Op::CombineTypes(..)) => self.compile_type(span),
+            // assertions are checked by the typechecker and carry no information at runtime
+            Expr::Assert(_) => self.compile_type(span),
             Expr::Some(e) => {
                 // wrap some value in Some by using builtin function mechanism
-                self.emit(Op::Builtin(Builtin::Some), 0);
+                self.emit(Op::Builtin(Builtin::Some), span.clone());
+                self.push_temporary();
                 self.compile(e)?;
-                self.emit(Op::Call(1), 0);
+                self.emit(Op::Call(1), span.clone());
+                self.pop_temporaries(1);
             },
 
 
@@ -314,11 +380,37 @@ impl Compiler {
             Expr::Annot(e, _) => {
                 self.compile(e)?;
             },
-            _ => todo!("{ast:?}")
+            Expr::Error => return Err(CompileError::InternalBug("Compiling an expression that failed to parse.".to_string())),
+            // the typechecker resolves imports and picks the alternative of `?` before compiling
+            Expr::Import(..) | Expr::Op(ast::Op::ImportAlt(..)) =>
+                return Err(CompileError::InternalBug(format!("Compiling unresolved import {}.", ast.expr))),
         };
         Ok(())
     }
 
+    fn compile_record(&mut self, items: Vec<(&String, &Node)>, span: Span) -> Result<(), CompileError> {
+        // fields are not in scope of each other, so they are kept as unnamed temporaries
+        for (s, e) in &items {
+            let c = self.add_constant(Value::String(s.to_string()));
+            self.emit(Op::Constant(c), e.span.clone());
+            self.push_temporary();
+            self.compile(e)?;
+            self.push_temporary();
+        }
+        self.emit(Op::CreateRecord(items.len()), span);
+        self.pop_temporaries(2 * items.len());
+        Ok(())
+    }
+
+    fn compile_binary_op(&mut self, l: &Node, r: &Node, op: Op, span: Span) -> Result<(), CompileError> {
+        self.compile(l)?;
+        self.push_temporary();
+        self.compile(r)?;
+        self.emit(op, span);
+        self.pop_temporaries(1);
+        Ok(())
+    }
+
     fn function(&mut self) -> &mut Function {
         &mut self.compilers.last_mut().unwrap().func
     }
@@ -328,11 +420,16 @@ impl Compiler {
         self.compilers.last_mut().unwrap()
     }
 
-    fn emit(&mut self, op: Op, span: usize) {
+    fn emit(&mut self, op: Op, span: Span) {
         self.function().chunk.push_op(op, span);
     }
-    fn emit_below(&mut self, depth: usize, op: Op, span: usize) {
-        self.function().chunk.push_op_below(depth, op, span);
+    // Emits a jump to be patched once its target is known, returning its index.
+    fn emit_jump(&mut self, op: Op, span: Span) -> usize {
+        self.emit(op, span);
+        self.function().chunk.code.len() - 1
+    }
+    fn patch_jump(&mut self, idx: usize) {
+        self.function().chunk.patch_jump(idx);
     }
     fn peek_op(&mut self) -> &Op {
         self.function().chunk.peek_op()
@@ -345,16 +442,16 @@ impl Compiler {
     fn begin_scope(&mut self) {
         self.compiler().scope_depth += 1;
     }
-    fn end_scope_with_result(&mut self) {
+    fn end_scope_with_result(&mut self, span: Span) {
         self.compiler().scope_depth -= 1;
 
         // TODO: this could be optimized by selectively closing, and then popping the rest in one go
         for j in (0..self.compiler().locals.len()).rev() {
             if self.compiler().locals[j].depth > self.compiler().scope_depth {
                 if self.compiler().locals[j].is_captured {
-                    self.emit(Op::CloseUpvalueBeneath, 0);
+                    self.emit(Op::CloseUpvalueBeneath, span.clone());
                 } else {
-                    self.emit(Op::PopBeneath, 0);
+                    self.emit(Op::PopBeneath, span.clone());
                 }
                 self.compiler().locals.pop();
             } else {
@@ -364,72 +461,78 @@ impl Compiler {
     }
 
 
-    // declaring a (local) variable is as simple as mapping the current stack top to a name
-    fn declare_variable(&mut self, name: String) -> Result<(), CompileError> {
-        let compiler_depth = self.compilers.len()-1;
+    // Types only matter to the typechecker, but may still be passed as arguments.
+    fn compile_type(&mut self, span: Span) {
+        let const_idx = self.add_constant(Value::Type);
+        self.emit(Op::Constant(const_idx), span);
+    }
+
+    // Intermediate values on the stack occupy a slot just like variables, but cannot be resolved by name.
+    fn push_temporary(&mut self) {
+        let depth = self.compiler().scope_depth;
+        self.compiler().locals.push(Local { depth, ..Local::default() });
+    }
+    fn pop_temporaries(&mut self, n: usize) {
         let c = self.compiler();
-        let local = Local { name, depth: c.scope_depth, is_captured: false };
-        if c.locals.contains(&local) {
-            Err(CompileError::VarRedefinition(local.name, 0))
-        } else {
-            println!("Declaring variable {local:?} at index={}, cdepth={}. Locals={:?}", c.locals.len(), compiler_depth, c.locals);
-            c.locals.push(local);
-            Ok(())
-        }
+        c.locals.truncate(c.locals.len() - n);
+    }
+
+    // declaring a (local) variable is as simple as mapping the current stack top to a name,
+    // an earlier binding of the same name stays reachable as `name@1`
+    fn declare_variable(&mut self, name: String, span: Span) {
+        let c = self.compiler();
+        let local = Local { name, depth: c.scope_depth, is_captured: false, span };
+        c.locals.push(local);
     }
 
-    // this must only be called in the current compiling
-    fn resolve_variable(&mut self, name: &str) -> Result<ResolvedVar, CompileError> {
+    // `x@n` skips the n innermost bindings of `x`, counting across enclosing functions
+    fn resolve_variable(&mut self, name: &str, idx: usize, span: Span) -> Result<ResolvedVar, CompileError> {
         let cidx = self.compilers.len()-1;
-        println!("Try Resolving {name} at cidx={cidx}");
 
-        if let Some(idx) = self.resolve_local_at_level(name, cidx, false) {
-            println!("Resolving {name} locally cidx={cidx}");
+        let mut skip = idx;
+        if let Some(idx) = self.resolve_local_at_level(name, &mut skip, cidx, false) {
             Ok(ResolvedVar::Local(idx))
         } else {
-            if let Some(upval_idx) = self.resolve_upvalue_at_level(name, cidx) {
+            if let Some(upval_idx) = self.resolve_upvalue_at_level(name, &mut skip, cidx) {
                 Ok(ResolvedVar::Upval(upval_idx))
             } else {
-                Err(CompileError::VarUndefined(name.to_string(), 0))
+                let location = Location::new(self.file.to_path_buf(), span);
+                Err(CompileError::InternalBug(format!("{location}: {name}@{idx} is not in scope.")))
             }
         }
 
     }
 
 
-    fn resolve_local_at_level(&mut self, name: &str, cidx: usize, capture: bool) -> Option<usize> {
+    // decrements `skip` for every shadowed binding of `name` passed on this level
+    fn resolve_local_at_level(&mut self, name: &str, skip: &mut usize, cidx: usize, capture: bool) -> Option<usize> {
         let compiler = self.compilers.get_mut(cidx).unwrap();
 
         for p in (0..compiler.locals.len()).rev() {
-            if &compiler.locals[p].name == name {
-                compiler.locals[p].is_captured = capture;
-                return Some(p);
+            if compiler.locals[p].name == name {
+                if *skip == 0 {
+                    compiler.locals[p].is_captured |= capture;
+                    return Some(p);
+                }
+                *skip -= 1;
             }
         }
-        return None;
+        None
     }
 
-    fn resolve_upvalue_at_level(&mut self, name: &str, cidx: usize) -> Option<usize> {
-        if cidx <= 1 {
+    fn resolve_upvalue_at_level(&mut self, name: &str, skip: &mut usize, cidx: usize) -> Option<usize> {
+        if cidx == 0 {
             return None;
         }
-        if let Some(stack_offset) = self.resolve_local_at_level(name, cidx-1, true) {
-            let up_idx = self.add_upvalue(UpvalueLoc::Local(stack_offset), cidx);
-            println!("Resolving {name} locally at cidx={}, upval_idx={up_idx}", cidx-1);
-            Some(up_idx)
+        if let Some(stack_offset) = self.resolve_local_at_level(name, skip, cidx-1, true) {
+            Some(self.add_upvalue(UpvalueLoc::Local(stack_offset), cidx))
         } else {
-            if let Some(up_idx) = self.resolve_upvalue_at_level(name, cidx - 1) {
-                let up_idx = self.add_upvalue(UpvalueLoc::Upval(up_idx), cidx);
-                println!("Adding upval at cidx={cidx}, upval_idx={up_idx}");
-                Some(up_idx)
-            } else {
-                None
-            }
+            self.resolve_upvalue_at_level(name, skip, cidx - 1)
+                .map(|up_idx| self.add_upvalue(UpvalueLoc::Upval(up_idx), cidx))
         }
     }
 
     fn add_upvalue(&mut self, upvalue: UpvalueLoc, cidx: usize) -> usize {
-        println!("Adding upvalue at cidx={cidx}");
         let up_idx = self.compilers[cidx].upvalues.len();
         self.compilers[cidx].upvalues.push(upvalue);
         up_idx
@@ -440,3 +543,38 @@ impl Compiler {
 
 
 
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{eval, parse2, vm};
+
+    // Compiles and runs `code` without type checking it first.
+    fn run_unchecked(code: &str) -> Result<Value, String> {
+        let (ast, errs) = parse2::parse(code);
+        assert!(errs.is_empty(), "{code:?} failed to parse: {errs:?}");
+        let function = compile(&ast.unwrap(), PathBuf::from("<test>.dhall")).map_err(|e| e.to_string())?;
+        vm::run_function(function, false).map_err(|e| e.to_string())
+    }
+
+    const SERVICE: &str = "let Service = { Type = { name : Text, port : Natural, tls : Bool }, default = { port = 80, tls = False } }";
+
+    #[test]
+    fn completion_fills_defaults() {
+        assert_eq!(eval(&format!("{SERVICE} in Service::{{ name = \"api\" }}")).unwrap(), "{ name = \"api\", port = 80, tls = False }");
+        assert_eq!(eval(&format!("{SERVICE} in Service::{{ name = \"db\", port = 5432 }}")).unwrap(), "{ name = \"db\", port = 5432, tls = False }");
+        assert_eq!(eval(&format!("{SERVICE} in [ (Service::{{ name = \"a\", tls = True }}).tls, (Service::{{ name = \"b\" }}).tls ]")).unwrap(), "[ True, False ]");
+    }
+
+    #[test]
+    fn ill_typed_input_is_an_internal_bug() {
+        let err = run_unchecked("\\(x : Natural) -> y").unwrap_err();
+        assert!(err.contains("Internal error") && err.contains("y@0 is not in scope"), "{err}");
+        let err = run_unchecked("{ Type = { a : Natural, b : Natural }, default = { a = 1 } }::{ c = 2 }").unwrap_err();
+        assert!(err.contains("Internal error") && err.contains("Completed record has the fields"), "{err}");
+        assert_eq!(run_unchecked("{ Type = { a : Natural, b : Natural }, default = { a = 1 } }::{ b = 2 }").unwrap().to_string(), "{ a = 1, b = 2 }");
+    }
+}
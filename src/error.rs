@@ -1,14 +1,350 @@
+use std::{cell::RefCell, collections::HashMap, path::{Path, PathBuf}, rc::Rc};
+
+use ariadne::{CharSet, Color, Config, Label, Report, ReportKind};
+use chumsky::error::{Simple, SimpleReason};
 use thiserror::Error;
 
-use crate::bytecode::Value;
+use crate::{bytecode::Value, ast::{Expr, Span}};
+
+
+thread_local! {
+    // Source code of every file that was read, so that reports can show snippets.
+    static SOURCES: RefCell<HashMap<PathBuf, Rc<str>>> = RefCell::new(HashMap::new());
+}
+
+// Reads a source file and remembers its content for error reports.
+pub fn read_source(file: &Path) -> std::io::Result<Rc<str>> {
+    let code: Rc<str> = std::fs::read_to_string(file)?.into();
+    register_source(file, code.clone());
+    Ok(code)
+}
+
+pub fn register_source(file: &Path, code: Rc<str>) {
+    SOURCES.with(|sources| sources.borrow_mut().insert(file.to_path_buf(), code));
+}
+
+fn source(file: &Path) -> Option<Rc<str>> {
+    SOURCES.with(|sources| sources.borrow().get(file).cloned())
+        .or_else(|| std::fs::read_to_string(file).ok().map(Rc::from))
+}
+
+
+// Source location of an expression: the file it was read from and its character range.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: PathBuf,
+    pub span: Span,
+}
+
+impl Location {
+    pub fn new(file: PathBuf, span: Span) -> Self {
+        Self { file, span }
+    }
+
+    // 1-based line and column of the start of the span, if the source is available.
+    pub fn line_col(&self) -> Option<(usize, usize)> {
+        let code = source(&self.file)?;
+        let prefix: Vec<char> = code.chars().take(self.span.start).collect();
+        let line = prefix.iter().filter(|c| **c == '\n').count() + 1;
+        let col = prefix.iter().rev().take_while(|c| **c != '\n').count() + 1;
+        Some((line, col))
+    }
+
+    // Shrink the span so that it does not start or end with whitespace.
+    fn trimmed(&self, code: &str) -> Span {
+        let chars: Vec<char> = code.chars().collect();
+        let end = self.span.end.min(chars.len());
+        let mut start = self.span.start.min(end);
+        let mut trimmed_end = end;
+        while start < trimmed_end && chars[start].is_whitespace() { start += 1 }
+        while trimmed_end > start && chars[trimmed_end - 1].is_whitespace() { trimmed_end -= 1 }
+        if start == trimmed_end { self.span.clone() } else { start..trimmed_end }
+    }
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line_col() {
+            Some((line, col)) => write!(f, "{}:{line}:{col}", self.file.display()),
+            None => write!(f, "{}:{}..{}", self.file.display(), self.span.start, self.span.end),
+        }
+    }
+}
 
 
-#[derive(Debug)]
-pub enum Error {
-    RuntimeError(RuntimeError),
-    CompileError(CompileError),
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Io,
+    Parse,
+    Type,
+    Compile,
+    Runtime,
 }
 
+// A diagnostic as reported to the user: a message pointing at a primary location,
+// optionally with labelled secondary locations, notes and a help text.
+#[derive(Debug, Clone)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub location: Option<Location>,
+    pub label: Option<String>,
+    pub secondary: Vec<(Location, String)>,
+    pub notes: Vec<String>,
+    pub help: Option<String>,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, message: impl ToString) -> Self {
+        Self {
+            kind,
+            message: message.to_string(),
+            location: None,
+            label: None,
+            secondary: Vec::new(),
+            notes: Vec::new(),
+            help: None,
+        }
+    }
+
+    pub fn with_location(mut self, location: Location) -> Self {
+        self.location = Some(location);
+        self
+    }
+
+    pub fn with_label(mut self, label: impl ToString) -> Self {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    pub fn with_secondary(mut self, location: Location, label: impl ToString) -> Self {
+        self.secondary.push((location, label.to_string()));
+        self
+    }
+
+    pub fn with_note(mut self, note: impl ToString) -> Self {
+        self.notes.push(note.to_string());
+        self
+    }
+
+    pub fn with_help(mut self, help: impl ToString) -> Self {
+        self.help = Some(help.to_string());
+        self
+    }
+
+    // Report for an error of the chumsky parser in `file`.
+    pub fn parse(file: &Path, err: &Simple<char>) -> Self {
+        let location = Location::new(file.to_path_buf(), err.span());
+        let found = match err.found() {
+            Some(c) => format!("{c:?}"),
+            None => "end of input".to_string(),
+        };
+        let mut error = match err.reason() {
+            SimpleReason::Unclosed { span, delimiter } => {
+                Error::new(ErrorKind::Parse, format!("Unclosed delimiter {delimiter:?}"))
+                    .with_label(format!("Expected closing delimiter, found {found}"))
+                    .with_secondary(Location::new(file.to_path_buf(), span.clone()), "Delimiter opened here")
+            },
+            SimpleReason::Unexpected => {
+                Error::new(ErrorKind::Parse, format!("Unexpected {found}"))
+                    .with_label(format!("Unexpected {found}"))
+            },
+            SimpleReason::Custom(msg) => Error::new(ErrorKind::Parse, msg).with_label(msg),
+        }.with_location(location);
+
+        if let Some(label) = err.label() {
+            error = error.with_note(format!("While parsing {label}"));
+        }
+        let mut expected: Vec<String> = err.expected()
+            .map(|e| match e {
+                Some(c) => format!("{c:?}"),
+                None => "end of input".to_string(),
+            })
+            .collect();
+        expected.sort();
+        if !expected.is_empty() {
+            error = error.with_note(format!("Expected one of {}", expected.join(", ")));
+        }
+        error
+    }
+
+    // Single report for all parse errors in `file`, the first one being the primary.
+    pub fn parse_all(file: &Path, errs: &[Simple<char>]) -> Self {
+        let Some((first, rest)) = errs.split_first() else {
+            return Error::new(ErrorKind::Parse, format!("Could not parse {}", file.display()));
+        };
+        let mut error = Error::parse(file, first);
+        for e in rest {
+            let e = Error::parse(file, e);
+            if let Some(location) = e.location {
+                error = error.with_secondary(location, e.message);
+            }
+        }
+        error
+    }
+
+    // Render the report with source snippets. Without `color`, the output is plain text.
+    pub fn render(&self, color: bool) -> String {
+        let Some(location) = &self.location else {
+            return self.render_plain();
+        };
+        let Some(code) = source(&location.file) else {
+            return self.render_plain();
+        };
+
+        let mut sources = vec![(file_id(&location.file), code.clone())];
+        for (loc, _) in &self.secondary {
+            if let Some(code) = source(&loc.file) {
+                sources.push((file_id(&loc.file), code));
+            }
+        }
+
+        let kind = match self.kind {
+            ErrorKind::Io => ReportKind::Custom("I/O error", Color::Red),
+            ErrorKind::Parse => ReportKind::Custom("Parse error", Color::Red),
+            ErrorKind::Type => ReportKind::Custom("Type error", Color::Red),
+            ErrorKind::Compile => ReportKind::Custom("Compile error", Color::Red),
+            ErrorKind::Runtime => ReportKind::Custom("Runtime error", Color::Red),
+        };
+        let span = location.trimmed(&code);
+        let mut primary = Label::new((file_id(&location.file), span.clone())).with_color(Color::Red);
+        if let Some(label) = &self.label {
+            primary = primary.with_message(label);
+        }
+        let mut report = Report::build(kind, file_id(&location.file), span.start)
+            .with_config(if color {
+                Config::default()
+            } else {
+                Config::default().with_color(false).with_char_set(CharSet::Ascii)
+            })
+            .with_message(&self.message)
+            .with_label(primary);
+        for (loc, label) in &self.secondary {
+            let span = match source(&loc.file) {
+                Some(code) => loc.trimmed(&code),
+                None => loc.span.clone(),
+            };
+            report = report.with_label(Label::new((file_id(&loc.file), span))
+                .with_message(label)
+                .with_color(Color::Blue));
+        }
+        if !self.notes.is_empty() {
+            report = report.with_note(self.notes.join("; "));
+        }
+        if let Some(help) = &self.help {
+            report = report.with_help(help);
+        }
+
+        let mut out = Vec::new();
+        if report.finish().write(ariadne::sources(sources), &mut out).is_err() {
+            return self.render_plain();
+        }
+        let out = String::from_utf8_lossy(&out).to_string();
+        // the report kind is colored regardless of the config
+        if color { out } else { strip_ansi(&out) }
+    }
+
+    fn render_plain(&self) -> String {
+        let mut out = self.to_string();
+        for (loc, label) in &self.secondary {
+            out += &format!("\n  {loc}: {label}");
+        }
+        for note in &self.notes {
+            out += &format!("\n  Note: {note}");
+        }
+        if let Some(help) = &self.help {
+            out += &format!("\n  Help: {help}");
+        }
+        out + "\n"
+    }
+
+    pub fn eprint(&self, color: bool) {
+        eprint!("{}", self.render(color));
+    }
+}
+
+fn strip_ansi(s: &str) -> String {
+    let mut out = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // skip the escape sequence up to and including its final letter
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() { break }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn file_id(file: &Path) -> String {
+    file.display().to_string()
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.kind {
+            ErrorKind::Io => "I/O error",
+            ErrorKind::Parse => "Parse error",
+            ErrorKind::Type => "Type error",
+            ErrorKind::Compile => "Compile error",
+            ErrorKind::Runtime => "Runtime error",
+        };
+        match &self.location {
+            Some(location) => write!(f, "{location}: {kind}: {}", self.message),
+            None => write!(f, "{kind}: {}", self.message),
+        }
+    }
+}
+
+impl From<RuntimeError> for Error {
+    fn from(err: RuntimeError) -> Self {
+        match err {
+            RuntimeError::At(location, err) => Error::from(*err).with_location(location),
+            err => Error::new(ErrorKind::Runtime, err),
+        }
+    }
+}
+
+impl From<CompileError> for Error {
+    fn from(err: CompileError) -> Self {
+        Error::new(ErrorKind::Compile, err)
+    }
+}
+
+impl From<TypeError> for Error {
+    fn from(err: TypeError) -> Self {
+        match err {
+            TypeError::At(location, err) => {
+                let error = Error::from(*err);
+                if error.location.is_some() { error } else { error.with_location(location) }
+            },
+            TypeError::Parse(file, errs) => Error::parse_all(&file, &errs),
+            TypeError::UnboundVariable(ref name) => Error::new(ErrorKind::Type, &err)
+                .with_label(format!("{name} is not in scope here"))
+                .with_help("Variables must be bound by a let expression or a function argument."),
+            TypeError::AnnotMismatch { .. } => Error::new(ErrorKind::Type, &err)
+                .with_label("The type of this expression does not match its annotation"),
+            TypeError::ArgMismatch { .. } => Error::new(ErrorKind::Type, &err)
+                .with_label("This argument has the wrong type"),
+            TypeError::Expected { ref expected, .. } => Error::new(ErrorKind::Type, &err)
+                .with_label(format!("Expected {expected} here")),
+            TypeError::NotAFunction(_) => Error::new(ErrorKind::Type, &err)
+                .with_label("This is not a function"),
+            TypeError::MissingCompletionFields(_) => Error::new(ErrorKind::Type, &err)
+                .with_label("This record completion lacks required fields")
+                .with_help("Add the fields to the record after ::, or give them a default in the schema."),
+            TypeError::AssertionFailed { ref diff, .. } => diff.iter().fold(
+                Error::new(ErrorKind::Type, &err).with_label("The two sides of this assertion normalize to different expressions"),
+                |error, difference| error.with_note(difference)),
+            err => Error::new(ErrorKind::Type, err),
+        }
+    }
+}
+
+
 #[derive(Error, Debug)]
 pub enum RuntimeError {
     #[error("Runtime encountered error during execution: {0}")]
@@ -20,17 +356,85 @@ pub enum RuntimeError {
     #[error("Cannot call value {0:?} as a function.")]
     FunctionCall(Value),
     #[error("Internal error (this is probably a bug): {0}")]
-    InternalBug(String)
+    InternalBug(String),
+    #[error("{0}: {1}")]
+    At(Location, Box<RuntimeError>),
 }
 
 #[derive(Error, Debug)]
 pub enum CompileError {
-    #[error("Invalid redifinition of variable in the same scope: {0}.")]
-    VarRedefinition(String, usize),     // varname, span
-    #[error("Trying to access undefined variable: {0}.")]
-    VarUndefined(String, usize),     // varname, span
     #[error("Internal error (this is probably a bug): {0}")]
     InternalBug(String),
+}
+
+#[derive(Error, Debug)]
+pub enum TypeError {
+    #[error("Trying to access undefined variable: {0}.")]
+    UnboundVariable(String),
+    #[error("Expression has type {found} but was annotated with {expected}.")]
+    AnnotMismatch { expected: Expr, found: Expr },
+    #[error("Function expects argument of type {expected}, got {found} instead.")]
+    ArgMismatch { expected: Expr, found: Expr },
+    #[error("Expected {expected}, got expression of type {found} instead.")]
+    Expected { expected: String, found: Expr },
+    #[error("Cannot apply expression of type {0} as a function.")]
+    NotAFunction(Expr),
+    #[error("Invalid function input type: {0}.")]
+    InvalidInputType(Expr),
+    #[error("Invalid function output type: {0}.")]
+    InvalidOutputType(Expr),
+    #[error("Invalid field type: {0}.")]
+    InvalidFieldType(Expr),
+    #[error("Invalid alternative type: {0}.")]
+    InvalidAlternativeType(Expr),
+    #[error("Invalid type for an empty list: {0}.")]
+    InvalidListType(Expr),
+    #[error("All list elements must have type {expected}, found {found}.")]
+    HeterogenousList { expected: Expr, found: Expr },
+    #[error("Both branches of if-then-else must have the same type: {0} vs {1}.")]
+    IfBranchMismatch(Expr, Expr),
+    #[error("Record has no field {0}.")]
+    MissingField(String),
+    #[error("Field {0} is projected more than once.")]
+    DuplicateProjection(String),
+    #[error("Field {field} has type {found} but the projection expects {expected}.")]
+    ProjectionMismatch { field: String, expected: Box<Expr>, found: Box<Expr> },
+    #[error("Union has no alternative {0}.")]
+    MissingAlternative(String),
+    #[error("No handler for alternative {0} in merge expression.")]
+    MissingHandler(String),
+    #[error("Handler {0} does not match any alternative.")]
+    UnusedHandler(String),
+    #[error("Handler {0} must be a function, got expression of type {1}.")]
+    HandlerNotAFunction(String, Expr),
+    #[error("Handler {handler} has type {found} where {expected} was expected.")]
+    HandlerMismatch { handler: String, expected: Box<Expr>, found: Box<Expr> },
+    #[error("The output type of handler {0} must not depend on its input.")]
+    DependentHandler(String),
+    #[error("Merging an empty union requires a type annotation.")]
+    EmptyMerge,
+    #[error("toMap of an empty record requires a type annotation.")]
+    EmptyToMap,
+    #[error("All fields converted by toMap must have type {expected}, found {found}.")]
+    HeterogenousToMap { expected: Box<Expr>, found: Box<Expr> },
+    #[error("Invalid type for toMap: {0}.")]
+    InvalidToMapType(Expr),
+    #[error("Updating an Optional must keep its content of type {expected}, found {found}.")]
+    WithOptionalMismatch { expected: Box<Expr>, found: Box<Expr> },
+    #[error("Record completion is missing the fields {} which have no default.", .0.join(", "))]
+    MissingCompletionFields(Vec<String>),
+    #[error("Field {0} collides when combining records.")]
+    FieldCollision(String),
+    #[error("Assertion failed: {left} is not equivalent to {right}.")]
+    AssertionFailed { left: Box<Expr>, right: Box<Expr>, diff: Vec<String> },
+    #[error("Expression {0} does not have a type.")]
+    Untyped(Expr),
+    #[error("Could not import {0}: {1}")]
+    Import(String, String),
+    #[error("Could not parse {}.", .0.display())]
+    Parse(PathBuf, Vec<Simple<char>>),
     #[error("Internal error (this is probably a bug): {0}")]
-    Basic(String),
-}
\ No newline at end of file
+    InternalBug(String),
+    #[error("{0}: {1}")]
+    At(Location, Box<TypeError>),
+}
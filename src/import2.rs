@@ -1,36 +1,318 @@
-use std::{collections::BTreeMap, path::{Path, PathBuf}, cell::RefCell};
+use std::{collections::BTreeMap, path::{Component, Path, PathBuf}, cell::RefCell, rc::Rc};
 
-use chumsky::Parser;
+use std::io::Read;
 
-use crate::{bytecode::Function, error::CompileError, compiler, parse2};
+use url::Url;
+
+use crate::{ast::Import, error};
 
 thread_local! {
-    static IMPORT_LOCAL: RefCell<BTreeMap<String, Function>> = RefCell::new(BTreeMap::new());
+    // Contents of every remote file fetched so far, so each URL is only requested once.
+    static REMOTE: RefCell<BTreeMap<String, Rc<[u8]>>> = const { RefCell::new(BTreeMap::new()) };
+    static FETCHER: RefCell<Rc<dyn Fetcher>> = RefCell::new(Rc::new(HttpFetcher));
+    static ENVIRONMENT: RefCell<Rc<dyn Environment>> = RefCell::new(Rc::new(ProcessEnv));
+}
+
+
+/*
+    Environment variables
+*/
+
+// Looks up `env:VAR` imports, so that tests can provide variables without the process environment.
+pub trait Environment {
+    fn var(&self, name: &str) -> Option<String>;
+}
+
+pub fn set_environment(env: impl Environment + 'static) {
+    ENVIRONMENT.with(|e| *e.borrow_mut() = Rc::new(env));
+}
+
+pub struct ProcessEnv;
+
+impl Environment for ProcessEnv {
+    fn var(&self, name: &str) -> Option<String> {
+        std::env::var(name).ok()
+    }
 }
 
+impl Environment for BTreeMap<String, String> {
+    fn var(&self, name: &str) -> Option<String> {
+        self.get(name).cloned()
+    }
+}
 
-pub fn import_file_local(path: &PathBuf) -> Result<Function, CompileError> {
-    let path_string = path.to_string_lossy().to_string();
+// Environment variables are identified as `env:VAR` wherever a file path is expected. Local
+// imports within them are resolved relative to the working directory.
+fn env_var(file: &Path) -> Option<&str> {
+    file.to_str()?.strip_prefix("env:")
+}
 
-    let cache_entry = IMPORT_LOCAL.with(|map| {
-        let map = map.borrow();
-        map.get(&path_string).cloned()
-    });
 
+/*
+    Fetching remote files
+*/
+
+pub struct Response {
+    pub body: Vec<u8>,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+// Retrieves remote files, so that tests can replace the network by something offline.
+pub trait Fetcher {
+    fn fetch(&self, url: &Url, headers: &[(String, String)]) -> Result<Response, String>;
+}
+
+pub fn set_fetcher(fetcher: impl Fetcher + 'static) {
+    FETCHER.with(|f| *f.borrow_mut() = Rc::new(fetcher));
+}
+
+pub struct HttpFetcher;
+
+impl Fetcher for HttpFetcher {
+    fn fetch(&self, url: &Url, headers: &[(String, String)]) -> Result<Response, String> {
+        let mut request = ureq::get(url.as_str());
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+        let response = request.call().map_err(|e| e.to_string())?;
+        let headers = response.headers_names().into_iter()
+            .filter_map(|name| response.header(&name).map(|v| (name.clone(), v.to_string())))
+            .collect();
+        let mut body = Vec::new();
+        response.into_reader().read_to_end(&mut body).map_err(|e| e.to_string())?;
+        Ok(Response { body, headers })
+    }
+}
+
+// Serves `scheme://host/path` from `root/host/path`. Response headers are read from an
+// optional `path.headers` file next to it, one `Name: value` per line.
+pub struct DirFetcher {
+    root: PathBuf,
+}
+
+impl DirFetcher {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+}
+
+impl Fetcher for DirFetcher {
+    fn fetch(&self, url: &Url, _headers: &[(String, String)]) -> Result<Response, String> {
+        let host = url.host_str().ok_or_else(|| format!("{url} has no host"))?;
+        let path = self.root.join(host).join(url.path().trim_start_matches('/'));
+        let body = std::fs::read(&path).map_err(|e| format!("{}: {e}", path.display()))?;
+        let mut header_file = path.into_os_string();
+        header_file.push(".headers");
+        let headers = std::fs::read_to_string(header_file).unwrap_or_default()
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        Ok(Response { body, headers })
+    }
+}
 
-    if let Some(func) = cache_entry {
-        Ok(func)
+
+/*
+    Locating imports
+*/
+
+// Remote files are identified by their URL wherever a file path is expected,
+// e.g. in error reports or as the importing file of nested imports.
+pub fn remote_url(file: &Path) -> Option<Url> {
+    let s = file.to_str()?;
+    if s.starts_with("http://") || s.starts_with("https://") {
+        Url::parse(s).ok()
     } else {
-        println!("Importing file {path_string}.");
-        let code = std::fs::read_to_string(path).unwrap();
-        let ast = parse2::dhall_parser().parse(code).unwrap();
+        None
+    }
+}
+
+// The file an import refers to, relative to the file containing it.
+pub fn locate(import: &Import, importing: &Path) -> Result<PathBuf, String> {
+    let parent = remote_url(importing);
+    match import {
+        Import::Remote(url, _) => {
+            let url = Url::parse(url).map_err(|e| format!("{url}: {e}"))?;
+            Ok(PathBuf::from(url.as_str()))
+        },
+        Import::Local(target) => match parent {
+            Some(url) if target.starts_with("./") || target.starts_with("../") => {
+                let url = url.join(target).map_err(|e| format!("{target}: {e}"))?;
+                Ok(PathBuf::from(url.as_str()))
+            },
+            // referential sanity: remote code must not depend on the machine it is evaluated on
+            Some(url) => Err(format!("{url} cannot import the local file {target}")),
+            None => {
+                let mut file_dir = importing.to_path_buf();
+                file_dir.pop();
+                std::fs::canonicalize(file_dir.join(target)).map_err(|e| e.to_string())
+            },
+        },
+        Import::Env(var) => match parent {
+            Some(url) => Err(format!("{url} cannot import the environment variable {var}")),
+            None => Ok(PathBuf::from(format!("env:{var}"))),
+        },
+    }
+}
+
+// What `as Location` evaluates to, as alternative of
+// `< Local : Text | Remote : Text | Environment : Text | Missing >` and its text.
+// Nothing is read, so the import does not need to exist.
+pub fn location(import: &Import, importing: &Path) -> Result<(&'static str, String), String> {
+    match import {
+        Import::Local(target) if remote_url(importing).is_none() => {
+            let mut file_dir = importing.to_path_buf();
+            file_dir.pop();
+            let path = std::path::absolute(file_dir.join(target)).map_err(|e| e.to_string())?;
+            let mut normalized = PathBuf::new();
+            for component in path.components() {
+                match component {
+                    Component::CurDir => (),
+                    Component::ParentDir => { normalized.pop(); },
+                    c => normalized.push(c),
+                }
+            }
+            Ok(("Local", normalized.to_string_lossy().to_string()))
+        },
+        // absolute paths within remote files are not relative to the url
+        Import::Local(target) if !(target.starts_with("./") || target.starts_with("../")) =>
+            Ok(("Local", target.clone())),
+        Import::Env(var) => Ok(("Environment", var.clone())),
+        _ => Ok(("Remote", locate(import, importing)?.to_string_lossy().to_string())),
+    }
+}
+
+// Reads the contents of a located import.
+pub fn read_import_bytes(path: &Path, headers: &[(String, String)], importing: &Path) -> Result<Rc<[u8]>, String> {
+    if let Some(var) = env_var(path) {
+        return ENVIRONMENT.with(|e| e.borrow().var(var))
+            .map(|value| value.into_bytes().into())
+            .ok_or_else(|| format!("Environment variable {var} is not set"));
+    }
+    let Some(url) = remote_url(path) else {
+        return std::fs::read(path).map(Rc::from).map_err(|e| e.to_string());
+    };
+    if let Some(body) = REMOTE.with(|map| map.borrow().get(url.as_str()).cloned()) {
+        return Ok(body);
+    }
+
+    let fetcher = FETCHER.with(|f| f.borrow().clone());
+    let response = fetcher.fetch(&url, headers)?;
+
+    // the equivalent of CORS: a server has to allow being imported from other origins
+    if let Some(parent) = remote_url(importing) {
+        let origin = parent.origin().ascii_serialization();
+        if parent.origin() != url.origin() {
+            match response.header("Access-Control-Allow-Origin") {
+                Some(allowed) if allowed.trim() == "*" || allowed.trim() == origin => (),
+                _ => return Err(format!("{url} does not allow being imported from {origin}")),
+            }
+        }
+    }
+
+    let body: Rc<[u8]> = response.body.into();
+    REMOTE.with(|map| map.borrow_mut().insert(url.to_string(), body.clone()));
+    Ok(body)
+}
 
-        let func = compiler::compile(&ast, path.clone()).unwrap();
+// Reads the source of a located import and registers it for error reports.
+pub fn read_import(path: &Path, headers: &[(String, String)], importing: &Path) -> Result<Rc<str>, String> {
+    let bytes = read_import_bytes(path, headers, importing)?;
+    let code: Rc<str> = std::str::from_utf8(&bytes)
+        .map_err(|e| format!("{} is not valid UTF-8: {e}", path.display()))?
+        .into();
+    error::register_source(path, code.clone());
+    Ok(code)
+}
 
-        IMPORT_LOCAL.with(|map|
-            map.borrow_mut()
-            .insert(path_string, func.clone()));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval;
 
-        Ok(func)
+    fn dhall_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("dhall")
     }
-}
\ No newline at end of file
+
+    type Headers = Vec<(String, String)>;
+
+    // Serves fixed files for urls, each with its response headers.
+    struct MapFetcher(BTreeMap<&'static str, (&'static str, Headers)>);
+
+    impl Fetcher for MapFetcher {
+        fn fetch(&self, url: &Url, _headers: &[(String, String)]) -> Result<Response, String> {
+            let (body, headers) = self.0.get(url.as_str()).ok_or_else(|| format!("{url} not found"))?;
+            Ok(Response { body: body.as_bytes().to_vec(), headers: headers.clone() })
+        }
+    }
+
+    fn allow(origin: &str) -> Headers {
+        vec![("access-control-allow-origin".to_string(), origin.to_string())]
+    }
+
+    #[test]
+    fn dir_fetcher() {
+        set_fetcher(DirFetcher::new(dhall_dir().join("remote")));
+        assert_eq!(eval("let P = https://prelude.example.com/package.dhall in P.greet \"x\"").unwrap(), "\"Hello, x!\"");
+        assert_eq!(eval("let P = https://prelude.example.com/package.dhall in P.not True").unwrap(), "False");
+        assert!(eval("https://prelude.example.com/missing.dhall").unwrap_err().contains("missing.dhall"));
+    }
+
+    #[test]
+    fn environment() {
+        set_environment(BTreeMap::from([
+            ("PORT".to_string(), "8080".to_string()),
+            ("GREETING".to_string(), "\"hi\"".to_string()),
+        ]));
+        assert_eq!(eval("env:PORT + 1").unwrap(), "8081");
+        assert_eq!(eval("env:GREETING ++ \"!\"").unwrap(), "\"hi!\"");
+        assert_eq!(eval("env:PORT as Text").unwrap(), "\"8080\"");
+        assert_eq!(eval("env:MISSING ? 1").unwrap(), "1");
+        assert!(eval("env:MISSING").unwrap_err().contains("MISSING is not set"));
+        // the process environment is not consulted
+        assert!(eval("env:PATH as Text").is_err());
+    }
+
+    #[test]
+    fn import_modes() {
+        assert_eq!(eval("./motd.txt as Text").unwrap(), "\"Welcome to dhalli!\\n\"");
+        assert_eq!(eval("./motd.txt as Bytes").unwrap(), "0x\"57656C636F6D6520746F206468616C6C69210A\"");
+        let motd = dhall_dir().join("motd.txt");
+        assert_eq!(eval("./motd.txt as Location").unwrap(), format!("Local \"{}\"", motd.display()));
+        assert_eq!(eval("../dhall/./missing.dhall as Location").unwrap(),
+            format!("Local \"{}\"", dhall_dir().join("missing.dhall").display()));
+        assert_eq!(eval("env:HOME as Location").unwrap(), "Environment \"HOME\"");
+        assert_eq!(eval("https://example.com/a/../b.dhall as Location").unwrap(), "Remote \"https://example.com/b.dhall\"");
+        assert_eq!(eval("showConstructor (./missing.dhall as Location)").unwrap(), "\"Local\"");
+    }
+
+    #[test]
+    fn cors() {
+        set_fetcher(MapFetcher(BTreeMap::from([
+            ("https://a.example.com/same.dhall", ("./other.dhall", Vec::new())),
+            ("https://a.example.com/other.dhall", ("1", Vec::new())),
+            ("https://a.example.com/denied.dhall", ("https://b.example.com/private.dhall", Vec::new())),
+            ("https://a.example.com/wildcard.dhall", ("https://b.example.com/public.dhall", Vec::new())),
+            ("https://a.example.com/origin.dhall", ("https://b.example.com/partner.dhall", Vec::new())),
+            ("https://b.example.com/private.dhall", ("2", Vec::new())),
+            ("https://b.example.com/public.dhall", ("3", allow("*"))),
+            ("https://b.example.com/partner.dhall", ("4", allow("https://a.example.com"))),
+        ])));
+        assert_eq!(eval("https://a.example.com/same.dhall").unwrap(), "1");
+        assert_eq!(eval("https://a.example.com/wildcard.dhall").unwrap(), "3");
+        assert_eq!(eval("https://a.example.com/origin.dhall").unwrap(), "4");
+        assert!(eval("https://a.example.com/denied.dhall").unwrap_err()
+            .contains("does not allow being imported from https://a.example.com"));
+        // local files may import anything
+        assert_eq!(eval("https://b.example.com/private.dhall").unwrap(), "2");
+    }
+}
@@ -1,84 +1,126 @@
-use std::path::PathBuf;
-use chumsky::Parser;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use ast::Expr;
+use bytecode::Value;
+use error::{Error, ErrorKind};
 
 
 mod parse2;
 mod ast;
-// mod interpret;
-// mod env;
-// mod import;
 mod import2;
 mod naive_double;
 mod bytecode;
 mod vm;
 mod compiler;
 mod error;
+mod typecheck;
 
 
 fn main() {
-    let filename = std::env::args().nth(1).expect("no file name given");
-    let path = std::fs::canonicalize(PathBuf::from(filename)).unwrap();
-    let code = std::fs::read_to_string(&path).unwrap();
-    let ast = parse2::dhall_parser().parse(code).unwrap();
-    println!("AST:");
-    println!("{:?}", &ast);
-
-    let function = compiler::compile(&ast, path);
-    println!("Function:");
-    println!("{:?}", &function);
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    // --debug shows the AST, the type and the bytecode, and traces the VM
+    let debug = match args.iter().position(|a| a == "--debug") {
+        Some(idx) => {
+            args.remove(idx);
+            true
+        },
+        None => false,
+    };
+    // --no-color (or NO_COLOR) renders plain error reports, e.g. for CI logs
+    let color = match args.iter().position(|a| a == "--no-color") {
+        Some(idx) => {
+            args.remove(idx);
+            false
+        },
+        None => std::env::var_os("NO_COLOR").is_none(),
+    };
+    // --remote-dir <dir> serves remote imports from <dir>/<host>/<path> instead of the network
+    if let Some(idx) = args.iter().position(|a| a == "--remote-dir") {
+        args.remove(idx);
+        if idx >= args.len() {
+            eprintln!("--remote-dir expects a directory");
+            std::process::exit(2);
+        }
+        import2::set_fetcher(import2::DirFetcher::new(PathBuf::from(args.remove(idx))));
+    }
+    // --env NAME=value (repeatable) replaces the process environment seen by `env:` imports
+    let mut env = BTreeMap::new();
+    while let Some(idx) = args.iter().position(|a| a == "--env") {
+        args.remove(idx);
+        match args.get(idx).and_then(|a| a.split_once('=')) {
+            Some((name, value)) => {
+                env.insert(name.to_string(), value.to_string());
+                args.remove(idx);
+            },
+            None => {
+                eprintln!("--env expects NAME=value");
+                std::process::exit(2);
+            },
+        }
+    }
+    if !env.is_empty() {
+        import2::set_environment(env);
+    }
+    let Some(filename) = args.first() else {
+        eprintln!("Usage: dhalli [--debug] [--no-color] [--remote-dir <dir>] [--env NAME=value] <file>");
+        std::process::exit(2);
+    };
 
-    let r = vm::run_function(function.unwrap(), true);
-    println!("{r:#?}");
+    if let Err(errors) = run(&PathBuf::from(filename), debug) {
+        for e in errors {
+            e.eprint(color);
+        }
+        std::process::exit(1);
+    }
 }
 
+fn run(filename: &Path, debug: bool) -> Result<(), Vec<Error>> {
+    let io_error = |e: std::io::Error| vec![Error::new(ErrorKind::Io, format!("{}: {e}", filename.display()))];
+    let path = std::fs::canonicalize(filename).map_err(io_error)?;
+    let code = error::read_source(&path).map_err(io_error)?;
+    let (_, r) = evaluate(&code, &path, debug)?;
+    println!("{r}");
+    Ok(())
+}
 
-// fn main2() {
-
-//     let filename = std::env::args().nth(1).expect("no file name given");
-
-//     let dhall_text = PathBuf::from(filename);
-
-//     // parse2
-//     let expr = import::parse_and_interpret(&dhall_text);
-//     if let Err(e) = &expr {
-//         // let re = Regex::new(r#"(?s)Error parsing file "(.*?)".*span: (\d+?)\.\.(\d+?),"#).unwrap();
-//         let err_str = format!("{:?}", e);
-//         let re_file = Regex::new(r#"Error parsing file "(.*?)""#).unwrap();
-//         let mut file_captures = re_file.captures_iter(&err_str);
-//         let mut file_capture = file_captures.next();
-//         for cap in file_captures {
-//             file_capture = Some(cap);
-//         }
+// Parses, resolves, type checks, compiles and runs `code` as if it was read from `path`.
+// Returns the type and the value of the expression.
+fn evaluate(code: &str, path: &Path, debug: bool) -> Result<(Expr, Value), Vec<Error>> {
+    let (ast, errs) = parse2::parse(code);
+    if !errs.is_empty() {
+        return Err(errs.iter().map(|e| Error::parse(path, e)).collect());
+    }
+    let Some(ast) = ast else {
+        return Err(vec![Error::new(ErrorKind::Parse, "Could not parse file.")]);
+    };
+    if debug {
+        println!("AST:");
+        println!("{:?}", &ast);
+    }
 
-//         if let Some(cap) = file_capture {
-//             let path = cap.get(1).unwrap().as_str();
+    let ast = typecheck::resolve(&ast, path).map_err(|e| vec![e.into()])?;
+    let t = typecheck::typecheck(&ast, path).map_err(|e| vec![e.into()])?;
+    if debug {
+        println!("Type: {t}");
+    }
 
-//             let re_range = Regex::new(r#"span: (\d+?)\.\.(\d+?),"#).unwrap();
-//             if let Some(caps) = re_range.captures(&err_str) {
-//                 let mut li = caps.get(1).unwrap().as_str().parse::<usize>().unwrap();
-//                 let mut ri = caps.get(2).unwrap().as_str().parse::<usize>().unwrap();
-//                 let code = std::fs::read_to_string(path).unwrap();
-//                 if !(li < 20) { li -= 20 } else { li = 0 }
-//                 if !(ri + 20 > code.len()) { ri += 20 } else { ri = code.len() }
-//                 let slice = &code.as_str()[li..ri];
-//                 println!("{slice}");
+    let function = compiler::compile(&ast, path.to_path_buf()).map_err(|e| vec![e.into()])?;
+    if debug {
+        println!("Function:");
+        println!("{:?}", &function.chunk);
+    }
 
-//             }
+    let r = vm::run_function(function, debug).map_err(|e| vec![e.into()])?;
+    Ok((t, r))
+}
 
-//         }
-//         // if let Some(caps) = re.captures(&format!("{e:?}")) {
-//         //     // println!()
-//         //     let path = caps.get(1).unwrap().as_str();
-//         //     let mut li = caps.get(2).unwrap().as_str().parse::<usize>().unwrap();
-//         //     let mut ri = caps.get(3).unwrap().as_str().parse::<usize>().unwrap();
-//         //     let code = std::fs::read_to_string(path).unwrap();
-//         //     // let code = std::fs::read_to_string(path).unwrap().split_at(li-10).1.to_string();
-//         //     // let code = code.split_at(ri-li+10).0.to_string();
-//         //     if !(li < 10) { li -= 10 } else { li = 0 }
-//         //     if !(ri + 10 > code.len()) { ri += 10 } else { ri = code.len() }
-//         //     let slice = &code.as_str()[li..ri];
-//         //     println!("{slice}");
-//         // }
-//     }
-//     println!("{expr:?}");
-// }
+// Evaluates `code` as if it was a file in the `dhall` directory of the repository.
+// Returns the value in Dhall syntax, or the messages of all errors.
+#[cfg(test)]
+fn eval(code: &str) -> Result<String, String> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("dhall").join("<test>.dhall");
+    evaluate(code, &path, false)
+        .map(|(_, r)| r.to_string())
+        .map_err(|errors| errors.iter().map(|e| e.message.clone()).collect::<Vec<_>>().join("\n"))
+}
@@ -2,6 +2,9 @@
 // This implementation of comparable f64 type was copied without
 // edit (stolen) from https://github.com/Nadrieril/dhall-rust
 
+use num_bigint::BigInt;
+use num_traits::{Signed, ToPrimitive};
+
 #[derive(Debug, Copy, Clone)]
 pub struct NaiveDouble(f64);
 
@@ -29,8 +32,57 @@ impl From<f64> for NaiveDouble {
     }
 }
 
+// Integer/toDouble: the nearest Double, integers beyond the range of a Double become +/-Infinity.
+impl From<&BigInt> for NaiveDouble {
+    fn from(i: &BigInt) -> Self {
+        let infinity = if i.is_negative() { f64::NEG_INFINITY } else { f64::INFINITY };
+        NaiveDouble(i.to_f64().filter(|x| x.is_finite()).unwrap_or(infinity))
+    }
+}
+
 impl From<NaiveDouble> for f64 {
     fn from(x: NaiveDouble) -> f64 {
         x.0
     }
-}
\ No newline at end of file
+}
+
+// Formatting of `Double/show`: decimal notation for 0.1 <= |x| < 10^7, scientific notation otherwise,
+// always with at least one fractional digit (e.g. `1.5`, `1.0e7`, `-2.5e-3`).
+impl std::fmt::Display for NaiveDouble {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let x = self.0;
+        if x.is_nan() {
+            return write!(f, "NaN");
+        }
+        if x.is_infinite() {
+            return write!(f, "{}Infinity", if x < 0.0 { "-" } else { "" });
+        }
+        if x == 0.0 {
+            return write!(f, "{}0.0", if x.is_sign_negative() { "-" } else { "" });
+        }
+
+        // shortest representation that round-trips, e.g. "-1.2345e3"
+        let sci = format!("{:e}", x.abs());
+        let (mantissa, exp) = sci.split_once('e').unwrap();
+        let exp: i32 = exp.parse().unwrap();
+        let digits = mantissa.replace('.', "");
+        let sign = if x < 0.0 { "-" } else { "" };
+
+        if (0.1..1e7).contains(&x.abs()) {
+            let (int, frac) = if exp < 0 {
+                ("0".to_string(), "0".repeat((-exp - 1) as usize) + &digits)
+            } else {
+                let point = exp as usize + 1;
+                let padded = format!("{digits:0<point$}");
+                let (int, frac) = padded.split_at(point);
+                (int.to_string(), frac.to_string())
+            };
+            let frac = if frac.is_empty() { "0" } else { &frac };
+            write!(f, "{sign}{int}.{frac}")
+        } else {
+            let (first, rest) = digits.split_at(1);
+            let rest = if rest.is_empty() { "0" } else { rest };
+            write!(f, "{sign}{first}.{rest}e{exp}")
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use std::collections::BTreeMap;
 
 use chumsky::prelude::*;
+use num_bigint::{BigInt, BigUint};
 
 use crate::{ast::*, naive_double::NaiveDouble, bytecode::Builtin};
 
@@ -12,34 +13,126 @@ macro_rules! padded {
     };
 }
 
-const KEYWORDS: &'static [&'static str] = &[
+const KEYWORDS: &[&str] = &[
     "if", "then", "else"
     , "let", "in"
     , "using", "missing"
     , "assert", "as"
     , "Infinity", "NaN"
-    , "merge", "Some", "toMap"
+    , "merge", "Some", "toMap", "showConstructor"
     , "forall"
     , "with"
 ];
 
 
 
+// What follows the dot of a selector expression: `.x`, `.{ x, y }` or `.(T)`.
+enum Selector {
+    Field(String),
+    Labels(Vec<String>),
+    Type(Node),
+}
+
 fn vec_to_string(vec: Vec<char>) -> String {
     vec.into_iter().collect()
 }
 
 
-fn create_deep_record_lit(name: &String, expr: Expr) -> (String, Expr) {
-    let mut names: Vec<String> = name.split('.').map(|s| s.to_string()).collect();
+// `{ a.b.c = e }` is sugar for `{ a = { b = { c = e } } }`
+fn create_deep_record_lit(mut names: Vec<String>, expr: Node) -> (String, Node) {
     let mut e = expr;
     while names.len() > 1 {
-        e = Expr::RecordLit(vec![(names.pop().unwrap(), e)]);
+        let span = e.span.clone();
+        e = Node::new(Expr::RecordLit(vec![(names.pop().unwrap(), e)]), span);
     }
 
     (names.pop().unwrap(), e)
 }
 
+fn binop(l: Node, r: Node, f: impl Fn(Box<Node>, Box<Node>) -> Expr) -> Node {
+    let span = l.span.start..r.span.end;
+    Node::new(f(Box::new(l), Box::new(r)), span)
+}
+
+// Merge consecutive literal chunks of a text literal into the (text, interpolation) pairs of `Expr::Text`.
+fn text_chunks(vec: Vec<Node>) -> Expr {
+    let mut result = Vec::new();
+    let mut s = "".to_string();
+    for e in vec {
+        if let Expr::TextLit(l) = &e.expr {
+            s = s + l;
+        } else {
+            result.push((s, Some(e)));
+            s = "".to_string();
+        }
+    }
+    if !s.is_empty() { result.push((s, None)) }
+    Expr::Text(result)
+}
+
+// Remove the indentation common to all lines of a single quote literal. Empty lines do not count,
+// except for the last one (the indentation of the closing quotes).
+fn strip_indentation(vec: Vec<Node>) -> Vec<Node> {
+    let mut lines: Vec<Vec<Node>> = vec![vec![]];
+    for e in vec {
+        match &e.expr {
+            Expr::TextLit(l) if l == "\n" => lines.push(vec![]),
+            _ => lines.last_mut().unwrap().push(e),
+        }
+    }
+
+    let indentation = |line: &[Node]| -> String {
+        line.iter()
+            .map_while(|e| match &e.expr {
+                Expr::TextLit(l) if l == " " || l == "\t" => Some(l.clone()),
+                _ => None,
+            })
+            .collect()
+    };
+    let last = lines.len() - 1;
+    let prefix = lines.iter().enumerate()
+        .filter(|(i, line)| !line.is_empty() || *i == last)
+        .map(|(_, line)| indentation(line))
+        .reduce(|a, b| a.chars().zip(b.chars()).take_while(|(x, y)| x == y).map(|(x, _)| x).collect())
+        .unwrap_or_default();
+
+    let mut result = Vec::new();
+    for (i, line) in lines.into_iter().enumerate() {
+        if i > 0 {
+            result.push(Node::from(Expr::TextLit("\n".to_string())));
+        }
+        // every indentation character is a chunk of its own
+        result.extend(line.into_iter().skip(prefix.len()));
+    }
+    result
+}
+
+// Escape sequence in a double quote literal, e.g. `\n` or `\u{1F600}`.
+fn double_quote_escaped() -> impl Parser<char, char, Error = Simple<char>> {
+    let unicode_escape = hexdig().repeated().exactly(4)
+        .or(hexdig().repeated().at_least(1).delimited_by(just('{'), just('}')))
+        .collect::<String>()
+        .try_map(|s, span| {
+            let c = u32::from_str_radix(&s, 16).ok()
+                .filter(|c| c & 0xFFFE != 0xFFFE)   // non-characters
+                .and_then(char::from_u32);          // surrogates and values beyond 0x10FFFF
+            c.ok_or_else(|| Simple::custom(span, format!("Invalid unicode code point {s}")))
+        });
+
+    let invalid = any().try_map(|c, span| Err(Simple::custom(span, format!("Invalid escape sequence \\{c}"))));
+
+    just('\\').ignore_then(choice((
+        one_of("\"$\\/"),
+        just('b').to('\u{8}'),
+        just('f').to('\u{c}'),
+        just('n').to('\n'),
+        just('r').to('\r'),
+        just('t').to('\t'),
+        just('u').ignore_then(unicode_escape),
+        invalid,
+    )))
+}
+
 fn alphanum() -> impl Parser<char, char, Error = Simple<char>> {
     filter(|c: &char| "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789".contains(*c))
 }
@@ -73,7 +166,7 @@ fn equiv() -> impl Parser<char, (), Error = Simple<char>> {
 fn http_path() -> impl Parser<char, String, Error = Simple<char>> {
     let scheme = just("http").ignore_then(just('s').or_not())
         .map(|opt_s| {
-            if let Some(_) = opt_s { "https".to_string() }
+            if opt_s.is_some() { "https".to_string() }
             else { "http".to_string() }
         });
     // let domainlabel = recursive(|_| alphanum().repeated().at_least(1)
@@ -98,14 +191,12 @@ fn http_path() -> impl Parser<char, String, Error = Simple<char>> {
     let url = url_chars().repeated().at_least(1)
         .map(|vec| vec.iter().collect::<String>());
 
-    let http_raw = scheme
+    scheme
         .then_ignore(just("://"))
         .then(url)
         .map(|(scheme, url)| {
             scheme + "://" + &url
-        });
-
-    http_raw  // for now
+        })  // for now
 }
 
 fn local_path() -> impl Parser<char, String, Error = Simple<char>> {
@@ -137,7 +228,7 @@ fn local_path() -> impl Parser<char, String, Error = Simple<char>> {
             s
         });
     let home_path = just('~').ignore_then(path.clone())
-        .map_with_span(|paths, span| {
+        .map(|paths| {
             let mut s = "~".to_string();
             for item in paths {
                 s = s + &item;
@@ -158,20 +249,48 @@ fn local_path() -> impl Parser<char, String, Error = Simple<char>> {
 
 
 fn env() -> impl Parser<char, String, Error = Simple<char>> {
-
-    just("env:").ignore_then(text::ident())
+    let bash_name = filter(|c: &char| c.is_ascii_alphabetic() || *c == '_')
+        .chain(filter(|c: &char| c.is_ascii_alphanumeric() || *c == '_').repeated())
+        .collect::<String>();
+    let escape = just('\\').ignore_then(choice((
+        just('"'),
+        just('\\'),
+        just('a').to('\u{7}'),
+        just('b').to('\u{8}'),
+        just('f').to('\u{c}'),
+        just('n').to('\n'),
+        just('r').to('\r'),
+        just('t').to('\t'),
+        just('v').to('\u{b}'),
+    )));
+    let posix_char = filter(|c: &char| matches!(c, '\u{20}'..='\u{21}' | '\u{23}'..='\u{5b}' | '\u{5d}'..='\u{7e}'));
+    let posix_name = escape.or(posix_char).repeated().at_least(1)
+        .collect::<String>()
+        .delimited_by(just('"'), just('"'));
+
+    just("env:").ignore_then(bash_name.or(posix_name))
 }
 
 
-fn import() -> impl Parser<char, Expr, Error = Simple<char>> {
-    let local = local_path().map(|path| Expr::Import(Import::Local(path)));
-    let env_var = env().map(|env| Expr::Import(Import::Env(env)));
-    let http = http_path().map(|url| Expr::Import(Import::Remote(url)));
+fn import(headers: impl Parser<char, Node, Error = Simple<char>> + Clone) -> impl Parser<char, Expr, Error = Simple<char>> {
+    let local = local_path().map(Import::Local);
+    let env_var = env().map(Import::Env);
+    let using = ws().ignore_then(text::keyword("using")).ignore_then(ws1()).ignore_then(headers);
+    let http = http_path().then(using.or_not())
+        .map(|(url, headers)| Import::Remote(url, headers.map(Box::new)));
+    let mode = ws().ignore_then(text::keyword("as")).ignore_then(ws1())
+        .ignore_then(choice((
+            text::keyword("Text").to(ImportMode::Text),
+            text::keyword("Bytes").to(ImportMode::Bytes),
+            text::keyword("Location").to(ImportMode::Location),
+        )));
 
     let hash = just("sha256:").ignore_then(hexdig().repeated().at_least(64)).ignored();
 
     local.or(env_var).or(http)
         .then_ignore(ws1().ignore_then(hash).or_not())
+        .then(mode.or_not())
+        .map(|(import, mode)| Expr::Import(import, mode.unwrap_or(ImportMode::Code)))
 }
 
 fn eol() -> impl Parser<char, (), Error = Simple<char>> {
@@ -213,53 +332,173 @@ fn ws1() -> impl Parser<char, (), Error = Simple<char>> {
 }
 
 
-fn natural() -> impl Parser<char, u64, Error = Simple<char>> {
-    text::digits(10).map(|s: String| s.parse::<u64>().unwrap())
+// The n of `x@n`. Indices beyond a usize cannot refer to a variable in scope anyway.
+fn de_bruijn_index() -> impl Parser<char, usize, Error = Simple<char>> {
+    natural_digits().validate(|n: BigUint, span, emit| {
+        usize::try_from(&n).unwrap_or_else(|_| {
+            emit(Simple::custom(span, format!("Variable index {n} is too large, there are never that many variables of one name in scope")));
+            usize::MAX
+        })
+    })
+}
+
+// Hexadecimal with a `0x` prefix, or decimal without leading zeros.
+fn natural_digits() -> impl Parser<char, BigUint, Error = Simple<char>> {
+    let hex = just("0x")
+        .ignore_then(filter(|c: &char| c.is_ascii_hexdigit()).repeated().at_least(1).collect::<String>())
+        .map(|s| BigUint::parse_bytes(s.as_bytes(), 16).unwrap());
+    let decimal = text::digits(10).validate(|s: String, span, emit| {
+        if s.len() > 1 && s.starts_with('0') {
+            emit(Simple::custom(span, format!("Leading zeros are not allowed in {s}")))
+        }
+        s.parse().unwrap()
+    });
+    hex.or(decimal)
+}
+
+fn bytes_literal() -> impl Parser<char, Expr, Error = Simple<char>> {
+    just("0x\"")
+        .ignore_then(filter(|c: &char| c.is_ascii_hexdigit()).repeated().collect::<String>())
+        .then_ignore(just('"'))
+        .validate(|s, span, emit| {
+            if s.len() % 2 != 0 {
+                emit(Simple::custom(span, format!("Bytes literal 0x\"{s}\" has an odd number of hex digits")))
+            }
+            let bytes = s.as_bytes().chunks(2)
+                .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap_or_default())
+                .collect();
+            Expr::BytesLit(bytes)
+        })
 }
 
 fn natural_literal() -> impl Parser<char, Expr, Error = Simple<char>> {
-    // TODO: add hex notation
-    natural().map(|u| Expr::NaturalLit(u))
+    natural_digits().map(Expr::NaturalLit)
 }
 
 fn integer_literal() -> impl Parser<char, Expr, Error = Simple<char>> {
-    just('+').or(just('-')).then(natural())
-        .map(|(s, u)| {
-            let mut i = u as i64;
-            if s == '-' { i = -i; }
-            Expr::IntegerLit(i)
+    just('+').or(just('-')).then(natural_digits())
+        .map(|(s, n)| {
+            let i = BigInt::from(n);
+            Expr::IntegerLit(if s == '-' { -i } else { i })
         })
 }
 
 fn double_literal() -> impl Parser<char, Expr, Error = Simple<char>> {
-    just('+').or(just('-')).or_not()
-    .then(text::digits(10))
-    .then_ignore(just('.'))
-    .then(text::digits(10))
-    .map(|((c, int), frac)| {
-        let mut f = (int + "." + &frac).parse::<f64>().unwrap();
-        if let Some(sign) = c {
-            if sign == '-' {
-                f = -f;
+    let sign = one_of("+-").or_not().map(|c| c.map(String::from).unwrap_or_default());
+    let exponent = one_of("eE")
+        .ignore_then(one_of("+-").or_not())
+        .then(text::digits(10))
+        .map(|(sign, digits): (Option<char>, String)| format!("e{}{digits}", sign.map(String::from).unwrap_or_default()));
+    let fraction = just('.')
+        .ignore_then(text::digits(10))
+        .map(|digits: String| format!(".{digits}"));
+    let numeric = sign
+        .then(text::digits(10))
+        .then(fraction.then(exponent.clone().or_not()).map(|(f, e)| f + &e.unwrap_or_default())
+            .or(exponent))
+        // out of range literals are rounded to +/-Infinity
+        .map(|((sign, int), rest)| (sign + &int + &rest).parse::<f64>().unwrap());
+
+    numeric
+        .or(just("-Infinity").to(f64::NEG_INFINITY))
+        .or(text::keyword("Infinity").to(f64::INFINITY))
+        .or(text::keyword("NaN").to(f64::NAN))
+        .map(|f| Expr::DoubleLit(NaiveDouble::from(f)))
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// A number directly followed by `-` or `:` can only be the start of a temporal literal.
+fn not_temporal() -> impl Parser<char, (), Error = Simple<char>> {
+    none_of("-:").ignored().or(end()).rewind()
+}
+
+// Dates, times and time zones as in RFC 3339. A date and time with an optional time zone
+// `2020-01-01T12:00:00+01:00` is a record with fields `date`, `time` and `timeZone`.
+fn temporal_literal() -> impl Parser<char, Expr, Error = Simple<char>> {
+    let digits = |n| filter(|c: &char| c.is_ascii_digit()).repeated().exactly(n).collect::<String>();
+    let full_date = digits(4).then_ignore(just('-'))
+        .then(digits(2)).then_ignore(just('-'))
+        .then(digits(2))
+        .validate(|((y, m), d), span, emit| {
+            let (y, m, d) = (y.parse().unwrap(), m.parse().unwrap(), d.parse().unwrap());
+            if !(1..=12).contains(&m) || d < 1 || d > days_in_month(y, m) {
+                emit(Simple::custom(span, format!("Invalid date {y:04}-{m:02}-{d:02}")))
             }
-        }
-        Expr::DoubleLit(NaiveDouble::from(f))
-    })
+            Expr::DateLit(y, m, d)
+        });
+    let partial_time = digits(2).then_ignore(just(':'))
+        .then(digits(2)).then_ignore(just(':'))
+        .then(digits(2))
+        .then(just('.').ignore_then(text::digits(10)).or_not())
+        .validate(|(((h, m), s), frac), span, emit| {
+            let (h, m, s) = (h.parse().unwrap(), m.parse().unwrap(), s.parse().unwrap());
+            if h >= 24 || m >= 60 || s >= 60 {
+                emit(Simple::custom(span, format!("Invalid time {h:02}:{m:02}:{s:02}")))
+            }
+            Expr::TimeLit(h, m, s, frac.unwrap_or_default())
+        });
+    let numoffset = one_of("+-")
+        .then(digits(2)).then_ignore(just(':'))
+        .then(digits(2))
+        .validate(|((sign, h), m), span, emit| {
+            let (h, m): (i16, i16) = (h.parse().unwrap(), m.parse().unwrap());
+            if h >= 24 || m >= 60 {
+                emit(Simple::custom(span, format!("Invalid time zone {sign}{h:02}:{m:02}")))
+            }
+            Expr::TimeZoneLit(if sign == '-' { -(h * 60 + m) } else { h * 60 + m })
+        });
+    let offset = just('Z').to(Expr::TimeZoneLit(0)).or(numoffset.clone());
+
+    let fields = |fields: Vec<(&str, Node)>| {
+        Expr::RecordLit(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    };
+    let date_time = full_date.map_with_span(Node::new)
+        .then(just('T').ignore_then(partial_time.map_with_span(Node::new))
+            .then(offset.clone().map_with_span(Node::new).or_not())
+            .or_not())
+        .map(move |(date, time)| match time {
+            None => date.expr,
+            Some((time, None)) => fields(vec![("date", date), ("time", time)]),
+            Some((time, Some(tz))) => fields(vec![("date", date), ("time", time), ("timeZone", tz)]),
+        });
+    let time = partial_time.map_with_span(Node::new)
+        .then(offset.map_with_span(Node::new).or_not())
+        .map(move |(time, tz)| match tz {
+            None => time.expr,
+            Some(tz) => fields(vec![("time", time), ("timeZone", tz)]),
+        });
+
+    date_time.or(time).or(numoffset)
 }
 
-fn label() -> impl Parser<char, String, Error = Simple<char>> {
-    // tODO: add quoted label
+fn is_simple_label_first_char(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
 
-    let first = filter(|c: &char| {
-        let c: String = c.to_lowercase().collect();
-        "abcdefghijklmnopqrstuvwxyz_".contains(&c)
-    });
-    let next = filter(|c: &char| {
-        let c: String = c.to_lowercase().collect();
-        "abcdefghijklmnopqrstuvwxyz0123456789_/-".contains(&c)
-    });
+fn is_simple_label_next_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '/' | '_')
+}
 
-    first.then(next.repeated())
+// Whether `s` can be written without backticks wherever a variable name is expected.
+pub fn is_simple_label(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars.next().is_some_and(is_simple_label_first_char)
+        && chars.all(is_simple_label_next_char)
+        && !KEYWORDS.contains(&s)
+        && builtin_from_name(s).is_none()
+}
+
+fn simple_label() -> impl Parser<char, String, Error = Simple<char>> {
+    filter(|c: &char| is_simple_label_first_char(*c))
+        .then(filter(|c: &char| is_simple_label_next_char(*c)).repeated())
         .try_map(|(first, mut others), span| {
             others.insert(0, first);
             let s = vec_to_string(others);
@@ -271,15 +510,37 @@ fn label() -> impl Parser<char, String, Error = Simple<char>> {
         })
 }
 
+// Any printable ASCII character except the backtick, e.g. `app.kubernetes.io/name` or `if`
+fn quoted_label() -> impl Parser<char, String, Error = Simple<char>> {
+    just('`')
+        .ignore_then(filter(|c: &char| matches!(c, '\x20'..='\x5F' | '\x61'..='\x7E')).repeated())
+        .then_ignore(just('`'))
+        .collect()
+}
+
+fn label() -> impl Parser<char, String, Error = Simple<char>> {
+    quoted_label().or(simple_label())
+}
+
 fn any_label_or_some() -> impl Parser<char, String, Error = Simple<char>> {
     label().or(just("Some").map(|s| s.to_string()))
 }
 
+// Builtin names can only be used as variables when quoted.
 fn nonreserved_label() -> impl Parser<char, String, Error = Simple<char>> {
-    label()
+    quoted_label().or(simple_label().try_map(|s, span| match builtin_from_name(&s) {
+        Some(_) => Err(Simple::custom(span, format!("{s} is a builtin and cannot be used as a variable name"))),
+        None => Ok(s),
+    }))
+}
+
+// Parses `code`, recovering from syntax errors where possible.
+// Returns the (possibly partial) AST together with all errors that were encountered.
+pub fn parse(code: &str) -> (Option<Node>, Vec<Simple<char>>) {
+    dhall_parser().parse_recovery(code)
 }
 
-pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
+pub fn dhall_parser() -> impl Parser<char, Node, Error = Simple<char>> {
 
 
     // Expression
@@ -292,29 +553,32 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
             .debug("Interpolation");
 
         let double_quote_chunk = interpolation.clone()
-            .or(none_of('"').map(|c| Expr::TextLit(c.to_string())))
+            .or(double_quote_escaped()
+                .or(none_of("\"\\"))
+                .map_with_span(|c, span| Node::new(Expr::TextLit(c.to_string()), span)))
             .debug("Double quote chunk");
 
         let double_quote_literal = just('"')
-            .ignore_then(double_quote_chunk.repeated().map(|vec| {
-                let mut result = Vec::new();
-                let mut s = "".to_string();
-                for e in vec {
-                    match e {
-                        Expr::TextLit(l) => s = s + &l,
-                        _ => {
-                            result.push((s, Some(e)));
-                            s = "".to_string();
-                        }
-                    }
-                }
-                if !s.is_empty() { result.push((s, None)) }
-                Expr::Text(result)
-            }))
+            .ignore_then(double_quote_chunk.repeated().map(text_chunks))
             .then_ignore(just('"'))
             .debug("Double quote literal");
 
-        let text_literal = double_quote_literal; // TODO: OR single quote literal
+        let end_of_line = just('\n').or(just('\r').ignore_then(just('\n')));
+        let single_quote_chunk = just("'''").to("''".to_string())
+            .or(just("''${").to("${".to_string()))
+            .or(just("\r\n").to("\n".to_string()))
+            .or(none_of('\'').map(|c: char| c.to_string()))
+            .or(just('\'').then_ignore(none_of('\'').rewind()).map(|c| c.to_string()))
+            .map_with_span(|s, span| Node::new(Expr::TextLit(s), span));
+
+        let single_quote_literal = just("''")
+            .ignore_then(end_of_line)
+            .ignore_then(interpolation.clone().or(single_quote_chunk).repeated())
+            .then_ignore(just("''"))
+            .map(|vec| text_chunks(strip_indentation(vec)))
+            .debug("Single quote literal");
+
+        let text_literal = double_quote_literal.or(single_quote_literal);
 
 
 
@@ -346,25 +610,18 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
 
         let record_literal_normal_entry = padded!(just('.')).ignore_then(any_label_or_some()).repeated()
             .then_ignore(padded!(just('=')))
-            .then(expression.clone())
-            .map(|(subnames, expr)| {
-                let mut subname = "".to_string();
-                for s in subnames {
-                    subname = subname + "." + &s;
-                }
-                (subname, expr)
-            });
+            .then(expression.clone());
 
 
         let record_literal_entry = recursive(|_a| {
             any_label_or_some()
                 .then(record_literal_normal_entry.or_not())
-                .map(|(mut name, nrm)| {
-                    if nrm.is_none() { (name.clone(), Expr::Var(Var(name, 0))) }
-                    else {
-                        let (subname, expr) = nrm.unwrap();
-                        name = name + &subname;
-                        (name, expr)
+                .map_with_span(|(name, nrm), span| {
+                    if let Some((mut subnames, expr)) = nrm {
+                        subnames.insert(0, name);
+                        create_deep_record_lit(subnames, expr)
+                    } else {
+                        (name.clone(), Node::new(Expr::Var(Var(name, 0)), span))
                     }
                 })
         });
@@ -374,29 +631,25 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
             .then_ignore(padded!(just(',')).or_not())
             .map(|(first, mut other)| {
                 other.insert(0, first);
-                let mut r = Vec::new();
-                for (s, mut e) in other.drain(..) {
-                    if s.contains('.') {
-                        let (n, e) = create_deep_record_lit(&s, e);
-                        r.push((n, e));
-                    } else {
-                        r.push((s, e));
-                    }
-                }
-                Expr::RecordLit(r)
+                Expr::RecordLit(other)
             });
 
         let non_empty_record_type_or_literal =
             non_empty_record_type.or(non_empty_record_literal);
 
 
-        let record_type_or_literal = non_empty_record_type_or_literal; // or empty
+        let empty_record_type = just('}').rewind().map(|_| Expr::RecordType(BTreeMap::new()));
+
+        let record_type_or_literal = non_empty_record_type_or_literal
+            .or(empty_record_literal)
+            .or(empty_record_type);
 
 
         let record = just('{')
             .ignore_then(padded!(just(',').or_not()))
             .ignore_then(record_type_or_literal)
             .then_ignore(padded!(just('}')))
+            .recover_with(nested_delimiters('{', '}', [('[', ']'), ('(', ')')], |_| Expr::Error))
             .labelled("record");
 
 
@@ -411,16 +664,22 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
             .map(|(first, mut others)| {
                 others.insert(0, first);
                 Expr::ListLit(others)
-            });
+            })
+            .recover_with(nested_delimiters('[', ']', [('{', '}'), ('(', ')')], |_| Expr::Error));
 
 
         // Let-In
+        // an invalid bound expression is skipped up to the next `let` or `in`
+        let let_binding_end = ws1().then(text::keyword("let").or(text::keyword("in"))).rewind();
+        let let_binding_value = expression.clone()
+            .recover_with(skip_parser(take_until(let_binding_end)
+                .map_with_span(|_, span| Node::new(Expr::Error, span))));
         let let_binding = padded!(just("let")
                 .ignore_then(ws1())
                 .ignore_then(padded!(nonreserved_label()))
                 .then(just(':').ignore_then(ws1()).ignore_then(expression.clone()).or_not())
                 .then_ignore(padded!(just('=')))
-                .then(expression.clone())
+                .then(let_binding_value)
             )
             .map(|((n, t), r)| {
                 (n, t, r)
@@ -432,8 +691,8 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
                 .then(expression.clone())
             )
             .labelled("let_in")
-            .map(|(vec, e): (Vec<(String, Option<Expr>, Expr)>, Expr)| {
-                Expr::LetIn(vec, Box::new(e))
+            .map_with_span(|(vec, e): (Vec<(String, Option<Node>, Node)>, Node), span| {
+                Node::new(Expr::LetIn(vec, Box::new(e)), span)
             });
 
 
@@ -449,17 +708,15 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
             .then_ignore(padded!(just(')')))
             .then_ignore(padded!(arrow()))
             .then(expression.clone())
-            .map(|((an, at), e)| {
-                Expr::Lambda(an, Box::new(at), Box::new(e))
+            .map_with_span(|((an, at), e), span| {
+                Node::new(Expr::Lambda(an, Box::new(at), Box::new(e)), span)
             });
 
 
         // primitive expressions
         let variable = nonreserved_label()
-            .then(padded!(just('@')).ignore_then(natural()).or_not())
-            .map(|(n, i)| {
-                Expr::Var( if let Some(i) = i { Var(n, i as usize) } else { Var(n, 0) })
-            });
+            .then(padded!(just('@')).ignore_then(de_bruijn_index()).or_not())
+            .map(|(n, i)| Expr::Var(Var(n, i.unwrap_or(0))));
 
         let identifier = builtin().or(variable); // builtin identifiers handled in interpreter?
 
@@ -482,64 +739,98 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
                 Expr::UnionType(map)
             });
 
+        let empty_union_type = padded!(just('<')).ignore_then(padded!(just('>')))
+            .to(Expr::UnionType(BTreeMap::new()));
+
         let union_decl = padded!(just('<')).ignore_then(padded!(just('|')).or_not())
-            .ignore_then(union_type).then_ignore(padded!(just('>')));
+            .ignore_then(union_type).then_ignore(padded!(just('>')))
+            .or(empty_union_type);
 
-        let primitive_expression = recursive(|_a: Recursive<char, Expr, Simple<char>>|
+        let primitive_expression = recursive(|_a: Recursive<char, Node, Simple<char>>|
             text_literal
+            .or(temporal_literal())
             .or(double_literal())
-            .or(natural_literal())
-            .or(integer_literal())
+            .or(bytes_literal())
+            .or(natural_literal().then_ignore(not_temporal()))
+            .or(integer_literal().then_ignore(not_temporal()))
             .or(record)
             .or(union_decl)
             .or(non_empty_list_literal)
             .or(identifier)
+            .map_with_span(Node::new)
             .or(padded!(just('(')).ignore_then(expression.clone()).then_ignore(ws().then_ignore(just(')')))));
 
         // operator expressions
 
-        let selector = label(); // TODO: or labels or type-selector
+        let labels = padded!(just('{'))
+            .ignore_then(padded!(just(',')).or_not())
+            .ignore_then(padded!(any_label_or_some()).separated_by(just(',')).allow_trailing())
+            .then_ignore(padded!(just('}')));
+        let type_selector = padded!(just('(')).ignore_then(expression.clone()).then_ignore(padded!(just(')')));
+
+        let selector = any_label_or_some().map(Selector::Field)
+            .or(labels.map(Selector::Labels))
+            .or(type_selector.map(Selector::Type));
 
         let selector_expression = recursive(|_| primitive_expression.clone()
-            .then(padded!(just('.')).ignore_then(selector).repeated())
+            .then(padded!(just('.')).ignore_then(selector.map_with_span(|s, span| (s, span))).repeated())
             .map(|(mut expr, sel)| {
-                for s in sel {
-                    expr = Expr::Select(Box::new(expr), s)
+                for (s, span) in sel {
+                    let span = expr.span.start..span.end;
+                    let e = Box::new(expr);
+                    let selected = match s {
+                        Selector::Field(k) => Expr::Select(e, k),
+                        Selector::Labels(labels) => Expr::Project(e, labels),
+                        Selector::Type(t) => Expr::ProjectType(e, Box::new(t)),
+                    };
+                    expr = Node::new(selected, span)
                 }
                 expr
             }));
 
         let completion_expression = selector_expression.clone()
             .then(padded!(just("::")).ignore_then(selector_expression.clone()).or_not())
-            .map(|(e, c)| {
-                if let Some(c) = c {
-                    // A :: r --> (A.default // r) : A.Type
-                    let left = Expr::Select(Box::new(e.clone()), "default".to_string());
-                    let prefer = Expr::Op(Op::Prefer(Box::new(left), Box::new(c)));
-                    let t = Expr::Select(Box::new(e), "Type".to_string());
-
-                    Expr::Annot(Box::new(prefer), Box::new(t))
-                } else { e }
+            .map_with_span(|(e, c), span| match c {
+                Some(c) => Node::new(Expr::Completion(Box::new(e), Box::new(c)), span),
+                None => e,
             });
 
-        let import_expression = recursive(|_| import()
+        let import_expression = recursive(|import_expression| import(import_expression).map_with_span(Node::new)
             .or(completion_expression)); // first below application, so referenced a lot
 
 
         let some_expression = just("Some").ignore_then(ws1()).ignore_then(import_expression.clone())
-            .map(|e| Expr::Some(Box::new(e)));
+            .map_with_span(|e, span| Node::new(Expr::Some(Box::new(e)), span));
+
+        let merge_expression = text::keyword("merge").ignore_then(ws1())
+            .ignore_then(import_expression.clone())
+            .then_ignore(ws())
+            .then(import_expression.clone())
+            .map_with_span(|(h, u), span| Node::new(Expr::Merge(Box::new(h), Box::new(u), None), span));
 
-        let first_application_expression = some_expression.or(import_expression.clone());
+        let to_map_expression = text::keyword("toMap").ignore_then(ws1())
+            .ignore_then(import_expression.clone())
+            .map_with_span(|e, span| Node::new(Expr::ToMap(Box::new(e), None), span));
+
+        let show_constructor_expression = text::keyword("showConstructor").ignore_then(ws1())
+            .ignore_then(import_expression.clone())
+            .map_with_span(|e, span| Node::new(Expr::ShowConstructor(Box::new(e)), span));
+
+        let first_application_expression = merge_expression
+            .or(some_expression)
+            .or(to_map_expression)
+            .or(show_constructor_expression)
+            .or(import_expression.clone());
 
         let application_expression = recursive(|_| first_application_expression
             .then(ws1().ignore_then(import_expression.clone()).repeated())
             .labelled("application_expression")
-            .map(|(e1, mut e2)| {
+            .map_with_span(|(e1, mut e2), span| {
                 if e2.is_empty() {
                     e1
                 } else {
                     e2.insert(0, e1);
-                    Expr::Application(e2)
+                    Node::new(Expr::Application(e2), span)
                 }
             }));
 
@@ -547,7 +838,7 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
             .then(padded!(just("!=")).ignore_then(application_expression.clone()).repeated())
             .map(|(mut l, vec)| {
                 for r in vec {
-                    l = Expr::NotEqual(Box::new(l), Box::new(r));
+                    l = binop(l, r, Expr::NotEqual);
                 }
                 l
             }));
@@ -556,7 +847,7 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
             .then(padded!(just("==")).ignore_then(not_equal_expression.clone()).repeated())
             .map(|(mut l, vec)| {
                 for r in vec {
-                    l = Expr::Equal(Box::new(l), Box::new(r));
+                    l = binop(l, r, Expr::Equal);
                 }
                 l
             }));
@@ -565,7 +856,7 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
             .then(padded!(just("*")).ignore_then(equal_expression.clone()).repeated())
             .map(|(mut l, vec)| {
                 for r in vec {
-                    l = Expr::Op(Op::Times(Box::new(l), Box::new(r)));
+                    l = binop(l, r, |l, r| Expr::Op(Op::Times(l, r)));
                 }
                 l
             }));
@@ -574,7 +865,7 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
             .then(padded!(just("//\\\\")).ignore_then(times_expression.clone()).repeated())
             .map(|(mut l, vec)| {
                 for r in vec {
-                    l = Expr::Op(Op::CombineTypes(Box::new(l), Box::new(r)));
+                    l = binop(l, r, |l, r| Expr::Op(Op::CombineTypes(l, r)));
                 }
                 l
             }));
@@ -583,7 +874,7 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
             .then(padded!(just("//")).ignore_then(combine_types_expression.clone()).repeated())
             .map(|(mut l, vec)| {
                 for r in vec {
-                    l = Expr::Prefer(Box::new(l), Box::new(r));
+                    l = binop(l, r, Expr::Prefer);
                 }
                 l
             }));
@@ -592,7 +883,7 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
             .then(padded!(just("/\\")).ignore_then(prefer_expression.clone()).repeated())
             .map(|(mut l, vec)| {
                 for r in vec {
-                    l = Expr::Combine(Box::new(l), Box::new(r));
+                    l = binop(l, r, Expr::Combine);
                 }
                 l
             }));
@@ -601,7 +892,7 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
             .then(padded!(just("&&")).ignore_then(combine_expression.clone()).repeated())
             .map(|(mut l, vec)| {
                 for r in vec {
-                    l = Expr::And(Box::new(l), Box::new(r));
+                    l = binop(l, r, Expr::And);
                 }
                 l
             }));
@@ -610,7 +901,7 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
             .then(padded!(just("#")).ignore_then(and_expression.clone()).repeated())
             .map(|(mut l, vec)| {
                 for r in vec {
-                    l = Expr::ListAppend(Box::new(l), Box::new(r));
+                    l = binop(l, r, Expr::ListAppend);
                 }
                 l
             }));
@@ -619,7 +910,7 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
             .then(padded!(just("++")).ignore_then(list_append_expression.clone()).repeated())
             .map(|(mut l, vec)| {
                 for r in vec {
-                    l = Expr::TextAppend(Box::new(l), Box::new(r));
+                    l = binop(l, r, Expr::TextAppend);
                 }
                 l
             }));
@@ -628,7 +919,7 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
             .then(just("+").ignore_then(ws1()).ignore_then(padded!(text_append_expression.clone())).repeated())
             .map(|(mut l, vec)| {
                 for r in vec {
-                    l = Expr::Plus(Box::new(l), Box::new(r));
+                    l = binop(l, r, Expr::Plus);
                 }
                 l
             }));
@@ -637,7 +928,7 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
             .then(padded!(just("||")).ignore_then(plus_expression.clone()).repeated())
             .map(|(mut l, vec)| {
                 for r in vec {
-                    l = Expr::Or(Box::new(l), Box::new(r));
+                    l = binop(l, r, Expr::Or);
                 }
                 l
             }));
@@ -646,7 +937,7 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
             .then(padded!(just("?")).ignore_then(or_expression.clone()).repeated())
             .map(|(mut l, vec)| {
                 for r in vec {
-                    l = Expr::Op(Op::ImportAlt(Box::new(l), Box::new(r)));
+                    l = binop(l, r, |l, r| Expr::Op(Op::ImportAlt(l, r)));
                 }
                 l
             }));
@@ -655,7 +946,7 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
             .then(padded!(equiv()).ignore_then(import_alt_expression.clone()).repeated())
             .map(|(mut l, vec)| {
                 for r in vec {
-                    l = Expr::Op(Op::Equivalent(Box::new(l), Box::new(r)));
+                    l = binop(l, r, |l, r| Expr::Op(Op::Equivalent(l, r)));
                 }
                 l
             }));
@@ -667,31 +958,35 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
         // annotated expression
         let annotated_expression = padded!(operator_expression.clone())
             .then(just(':').ignore_then(ws1()).ignore_then(expression.clone()).or_not())
-            .map(|(e, t): (Expr, Option<Expr>)| {
-                if let Some(t) = t { Expr::Annot(Box::new(e), Box::new(t)) } else { e }
+            .map_with_span(|(e, t): (Node, Option<Node>), span| {
+                match (e, t) {
+                    // the annotation of `merge h u : T` and `toMap e : T` is part of the expression
+                    (Node { expr: Expr::Merge(h, u, None), .. }, Some(t)) => Node::new(Expr::Merge(h, u, Some(Box::new(t))), span),
+                    (Node { expr: Expr::ToMap(e, None), .. }, Some(t)) => Node::new(Expr::ToMap(e, Some(Box::new(t))), span),
+                    (e, Some(t)) => Node::new(Expr::Annot(Box::new(e), Box::new(t)), span),
+                    (e, None) => e,
+                }
             });
 
 
         // with expression
 
-        let with_clause = any_label_or_some().then(padded!(just('.')).ignore_then(any_label_or_some()).repeated())
+        let with_component = || any_label_or_some().map(PathComponent::Label)
+            .or(just('?').to(PathComponent::DescendOptional));
+        let with_clause = with_component().then(padded!(just('.')).ignore_then(with_component()).repeated())
             .then_ignore(padded!(just('=')))
             .then(operator_expression.clone())
-            .map(|((mut name, subnames), e)| {
-                for sn in subnames {
-                    name = name + "." + &sn;
-                }
-                let (n, e) = create_deep_record_lit(&name, e);
-                Expr::RecordLit(vec![(n, e)])
+            .map(|((first, mut path), v)| {
+                path.insert(0, first);
+                (path, v)
             });
 
+        // binds more loosely than application, so that `Some x with ? = y` updates `Some x`
         let with_expression =
-            // import_expression.clone().then(ws1().then_ignore(just("with")).then_ignore(ws1()).then(with_clause).repeated().at_least(1))
-            import_expression.clone().then(ws().ignore_then(just("with")).ignore_then(ws1()).ignore_then(with_clause).repeated().at_least(1))
-            .map(|(mut e, with_vec): (Expr, Vec<Expr>)| {
-                for expr in with_vec {
-                    e = Expr::Prefer(Box::new(e), Box::new(expr));
-                    todo!("Check if deep record creation case possible");
+            application_expression.clone().then(ws().ignore_then(text::keyword("with")).ignore_then(ws1()).ignore_then(with_clause).repeated().at_least(1))
+            .map(|(mut e, clauses): (Node, Vec<(Vec<PathComponent>, Node)>)| {
+                for (path, v) in clauses {
+                    e = binop(e, v, |e, v| Expr::With(e, path.clone(), v));
                 }
                 e
             });
@@ -704,15 +999,15 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
             .then(padded!(expression.clone()))
             .then_ignore(just("else").then_ignore(ws1()))
             .then(padded!(expression.clone()))
-            .map(|((ife, thene), elsee): ((Expr, Expr), Expr)| {
-                Expr::IfThenElse(Box::new(ife), Box::new(thene), Box::new(elsee))
+            .map_with_span(|((ife, thene), elsee): ((Node, Node), Node), span| {
+                Node::new(Expr::IfThenElse(Box::new(ife), Box::new(thene), Box::new(elsee)), span)
             });
 
         // fn type
         let fn_type = padded!(operator_expression
             .then_ignore(arrow()))
             .then(expression.clone())
-            .map(|(l, r)| Expr::FnType(Box::new(l), Box::new(r)));
+            .map(|(l, r)| binop(l, r, |l, r| Expr::FnType("_".to_string(), l, r)));
 
 
         // empty list
@@ -721,16 +1016,13 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
             .ignore_then(padded!(just(']')))
             .ignore_then(just(':').then(ws1()))
             .ignore_then(application_expression.clone())
-            .map(|_e| {
-                // Todo: how to handle types of lists?
-                Expr::ListLit(Vec::new())
-            });
+            .map_with_span(|t, span| Node::new(Expr::EmptyListLit(Box::new(t)), span));
 
         // assert
         let assert = padded!(just("assert"))
             .ignore_then(just(':').then(ws1()))
             .ignore_then(expression.clone())
-            .map(|expr| Expr::Assert(Box::new(expr)));
+            .map_with_span(|expr, span| Node::new(Expr::Assert(Box::new(expr)), span));
 
         // forall
         let forall = padded!(just("forall").or(just("∀"))
@@ -740,8 +1032,8 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
             .then(padded!(expression.clone()))
             .then_ignore(just(')').then_ignore(padded!(arrow())))
             .then(padded!(expression.clone()))
-            .map(|((_n, l), r): ((String, Expr), Expr)| {
-                Expr::FnType(Box::new(l), Box::new(r))
+            .map_with_span(|((n, l), r): ((String, Node), Node), span| {
+                Node::new(Expr::FnType(n, Box::new(l), Box::new(r)), span)
             }));
 
 
@@ -760,47 +1052,57 @@ pub fn dhall_parser() -> impl Parser<char, Expr, Error = Simple<char>> {
     })).then_ignore(end())
 }
 
+pub fn builtin_from_name(name: &str) -> Option<Builtin> {
+    match name {
+        "Natural/subtract"  => Some(Builtin::NaturalSubtract),
+        "Natural/fold"      => Some(Builtin::NaturalFold),
+        "Natural/build"     => Some(Builtin::NaturalBuild),
+        "Natural/isZero"    => Some(Builtin::NaturalIsZero),
+        "Natural/even"      => Some(Builtin::NaturalEven),
+        "Natural/odd"       => Some(Builtin::NaturalOdd),
+        "Natural/toInteger" => Some(Builtin::NaturalToInteger),
+        "Natural/show"      => Some(Builtin::NaturalShow),
+        "Integer/toDouble"  => Some(Builtin::IntegerToDouble),
+        "Integer/show"      => Some(Builtin::IntegerShow),
+        "Integer/negate"    => Some(Builtin::IntegerNegate),
+        "Integer/clamp"     => Some(Builtin::IntegerClamp),
+        "Double/show"       => Some(Builtin::DoubleShow),
+        "List/build"        => Some(Builtin::ListBuild),
+        "List/fold"         => Some(Builtin::ListFold),
+        "List/length"       => Some(Builtin::ListLength),
+        "List/head"         => Some(Builtin::ListHead),
+        "List/last"         => Some(Builtin::ListLast),
+        "List/indexed"      => Some(Builtin::ListIndexed),
+        "List/reverse"      => Some(Builtin::ListReverse),
+        "Text/show"         => Some(Builtin::TextShow),
+        "Text/replace"      => Some(Builtin::TextReplace),
+        "Date/show"         => Some(Builtin::DateShow),
+        "Time/show"         => Some(Builtin::TimeShow),
+        "TimeZone/show"     => Some(Builtin::TimeZoneShow),
+        "Bool"              => Some(Builtin::Bool),
+        "True"              => Some(Builtin::True),
+        "False"             => Some(Builtin::False),
+        "Optional"          => Some(Builtin::Optional),
+        "None"              => Some(Builtin::None),
+        "Natural"           => Some(Builtin::Natural),
+        "Integer"           => Some(Builtin::Integer),
+        "Double"            => Some(Builtin::Double),
+        "Text"              => Some(Builtin::Text),
+        "Bytes"             => Some(Builtin::Bytes),
+        "List"              => Some(Builtin::List),
+        "Date"              => Some(Builtin::Date),
+        "Time"              => Some(Builtin::Time),
+        "TimeZone"          => Some(Builtin::TimeZone),
+        "Type"              => Some(Builtin::Type),
+        "Kind"              => Some(Builtin::Kind),
+        "Sort"              => Some(Builtin::Sort),
+        _                   => None,
+    }
+}
+
 fn builtin() -> impl Parser<char, Expr, Error = Simple<char>> {
-    nonreserved_label().try_map(|s, span| {
-        let b = match s.as_str() {
-            "Natural/subtract"  => Some(Builtin::NaturalSubtract),
-            "Natural/fold"      => Some(Builtin::NaturalFold),
-            "Natural/build"     => Some(Builtin::NaturalBuild),
-            "Natural/isZero"    => Some(Builtin::NaturalIsZero),
-            "Natural/even"      => Some(Builtin::NaturalEven),
-            "Natural/odd"       => Some(Builtin::NaturalOdd),
-            "Natural/toInteger" => Some(Builtin::NaturalToInteger),
-            "Natural/show"      => Some(Builtin::NaturalShow),
-            "Integer/toDouble"  => Some(Builtin::IntegerToDouble),
-            "Integer/show"      => Some(Builtin::IntegerShow),
-            "Integer/negate"    => Some(Builtin::IntegerNegate),
-            "Integer/clamp"     => Some(Builtin::IntegerClamp),
-            "Double/show"       => Some(Builtin::DoubleShow),
-            "List/build"        => Some(Builtin::ListBuild),
-            "List/fold"         => Some(Builtin::ListFold),
-            "List/length"       => Some(Builtin::ListLength),
-            "List/head"         => Some(Builtin::ListHead),
-            "List/last"         => Some(Builtin::ListLast),
-            "List/indexed"      => Some(Builtin::ListIndexed),
-            "List/reverse"      => Some(Builtin::ListReverse),
-            "Text/show"         => Some(Builtin::TextShow),
-            "Text/replace"      => Some(Builtin::TextReplace),
-            "Bool"              => Some(Builtin::Bool),
-            "True"              => Some(Builtin::True),
-            "False"             => Some(Builtin::False),
-            "Optional"          => Some(Builtin::Optional),
-            "None"              => Some(Builtin::None),
-            "Natural"           => Some(Builtin::Natural),
-            "Integer"           => Some(Builtin::Integer),
-            "Double"            => Some(Builtin::Double),
-            "Text"              => Some(Builtin::Text),
-            "List"              => Some(Builtin::List),
-            "Type"              => Some(Builtin::Type),
-            "Kind"              => Some(Builtin::Kind),
-            "Sort"              => Some(Builtin::Sort),
-            _                   => None,
-        };
-        if let Some(b) = b {
+    simple_label().try_map(|s, span| {
+        if let Some(b) = builtin_from_name(&s) {
             match b {
                 Builtin::True => Ok(Expr::BoolLit(true)),
                 Builtin::False => Ok(Expr::BoolLit(false)),
@@ -810,4 +1112,95 @@ fn builtin() -> impl Parser<char, Expr, Error = Simple<char>> {
             Err(Simple::custom(span, ""))
         }
     })
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use chumsky::error::SimpleReason;
+
+    use super::*;
+
+    fn parse_ok(code: &str) -> Expr {
+        let (ast, errs) = parse(code);
+        assert!(errs.is_empty(), "{code:?} failed to parse: {errs:?}");
+        ast.unwrap().expr
+    }
+
+    #[test]
+    fn empty_union_type() {
+        assert_eq!(parse_ok("<>"), Expr::UnionType(BTreeMap::new()));
+        assert_eq!(parse_ok("< >"), Expr::UnionType(BTreeMap::new()));
+    }
+
+    fn text(s: &str) -> Expr {
+        Expr::Text(vec![(s.to_string(), None)])
+    }
+
+    #[test]
+    fn double_quoted_escapes() {
+        assert_eq!(parse_ok(r#""a\nb\tc""#), text("a\nb\tc"));
+        assert_eq!(parse_ok(r#""\"\\\/\$""#), text("\"\\/$"));
+        assert_eq!(parse_ok(r#""é\u{1F600}\u{0}""#), text("é😀\0"));
+        assert!(!parse(r#""\q""#).1.is_empty());
+    }
+
+    #[test]
+    fn single_quoted_indentation() {
+        assert_eq!(parse_ok("''\n    foo\n      bar\n    ''"), text("foo\n  bar\n"));
+        // the closing quotes count towards the common indentation
+        assert_eq!(parse_ok("''\n    foo\n  ''"), text("  foo\n"));
+        // blank lines do not
+        assert_eq!(parse_ok("''\n  a\n\n  b''"), text("a\n\nb"));
+        assert_eq!(parse_ok("''\n  a ''${b}\n  '''x\n  ''"), text("a ${b}\n''x\n"));
+    }
+
+    #[test]
+    fn natural_literals() {
+        assert_eq!(parse_ok("0x1F"), Expr::NaturalLit(31u32.into()));
+        assert_eq!(parse_ok("0xff"), Expr::NaturalLit(255u32.into()));
+        assert_eq!(parse_ok("0"), Expr::NaturalLit(0u32.into()));
+        assert_eq!(parse_ok("-0x10"), Expr::IntegerLit((-16).into()));
+        let (_, errs) = parse("007");
+        assert_eq!(errs.len(), 1);
+        assert!(matches!(errs[0].reason(), SimpleReason::Custom(msg) if msg.contains("Leading zeros")));
+    }
+
+    #[test]
+    fn quoted_labels() {
+        let Expr::Select(record, field) = parse_ok("{ `a b` = 1, `if` = 2 }.`a b`") else { panic!() };
+        assert_eq!(field, "a b");
+        let Expr::RecordLit(fields) = &record.expr else { panic!() };
+        let labels: Vec<_> = fields.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(labels, ["a b", "if"]);
+        assert_eq!(crate::eval("{ `a b` = 1, `if` = 2 }").unwrap(), "{ `a b` = 1, `if` = 2 }");
+    }
+
+    #[test]
+    fn keywords_are_not_variables() {
+        for code in ["\\(showConstructor : Bool) -> showConstructor", "let toMap = 1 in toMap"] {
+            assert!(!parse(code).1.is_empty(), "{code:?} should not parse");
+        }
+        assert!(matches!(parse_ok("\\(`showConstructor` : Bool) -> `showConstructor`"), Expr::Lambda(x, ..) if x == "showConstructor"));
+        assert_eq!(crate::eval("{ `showConstructor` = 1 }").unwrap(), "{ `showConstructor` = 1 }");
+    }
+
+    #[test]
+    fn with_binds_looser_than_application() {
+        let Expr::With(e, path, _) = parse_ok("None Natural with ? = 1") else { panic!() };
+        assert!(matches!(e.expr, Expr::Application(_)));
+        assert_eq!(path, [PathComponent::DescendOptional]);
+        let Expr::With(e, _, _) = parse_ok("Some 1 with ? = 2") else { panic!() };
+        assert!(matches!(e.expr, Expr::Some(_)));
+        let Expr::With(_, _, v) = parse_ok("r with a = 1 + 2") else { panic!() };
+        assert!(matches!(v.expr, Expr::Plus(..)));
+    }
+
+    #[test]
+    fn de_bruijn_indices() {
+        assert_eq!(parse_ok("x@2"), Expr::Var(Var("x".to_string(), 2)));
+        assert_eq!(parse_ok("x @ 0x10"), Expr::Var(Var("x".to_string(), 16)));
+        let (_, errs) = parse("x@123456789012345678901234567890");
+        assert_eq!(errs.len(), 1);
+        assert!(matches!(errs[0].reason(), SimpleReason::Custom(msg) if msg.starts_with("Variable index 123456789012345678901234567890 is too large")));
+    }
+}
@@ -0,0 +1,1671 @@
+use std::{collections::{BTreeMap, HashMap}, path::{Path, PathBuf}, cell::{OnceCell, RefCell}, rc::Rc};
+
+use chumsky::Parser;
+use num_bigint::{BigInt, BigUint};
+use num_traits::{One, Zero};
+
+use crate::ast::{Expr, Node, Op, Var, Import, ImportMode, PathComponent, Span};
+use crate::bytecode::Builtin;
+use crate::error::{TypeError, Location};
+use crate::{import2, parse2};
+
+thread_local! {
+    static RESOLVED: RefCell<BTreeMap<(PathBuf, ImportMode), Expr>> = const { RefCell::new(BTreeMap::new()) };
+    static BUILTIN_TYPES: RefCell<HashMap<Builtin, Expr>> = RefCell::new(HashMap::new());
+}
+
+
+// Replaces all imports of `node` by the expressions they refer to, relative to `file`. This is
+// done once, so the typechecker and the compiler both work on the same resolved expression.
+pub fn resolve(node: &Node, file: &Path) -> Result<Node, TypeError> {
+    let expr = resolve_imports(node, file, &mut Vec::new()).map_err(|e| locate_at(file, &node.span, e))?;
+    Ok(Node::new(expr, node.span.clone()))
+}
+
+// Infers the type of the resolved expression `node` from `file`.
+pub fn typecheck(node: &Node, file: &Path) -> Result<Expr, TypeError> {
+    type_node(&Context::new(file), node)
+}
+
+
+fn bx(e: Expr) -> Box<Node> {
+    Box::new(e.into())
+}
+
+// Box `e` as a node with the span of the node `n` it was derived from.
+fn respan(n: &Node, e: Expr) -> Box<Node> {
+    Box::new(Node::new(e, n.span.clone()))
+}
+
+fn var(name: &str, idx: usize) -> Expr {
+    Expr::Var(Var(name.to_string(), idx))
+}
+
+fn builtin(b: Builtin) -> Expr {
+    Expr::Builtin(b)
+}
+
+fn app(f: Expr, args: Vec<Expr>) -> Expr {
+    let mut vec = vec![f.into()];
+    vec.extend(args.into_iter().map(Node::from));
+    Expr::Application(vec)
+}
+
+fn text(s: String) -> Expr {
+    if s.is_empty() { Expr::Text(Vec::new()) } else { Expr::Text(vec![(s, None)]) }
+}
+
+
+/*
+    Import resolution
+*/
+
+fn resolve_imports(expr: &Expr, file: &Path, stack: &mut Vec<PathBuf>) -> Result<Expr, TypeError> {
+    match expr {
+        Expr::Import(import, mode) => resolve_import(import, *mode, file, stack),
+        Expr::Op(Op::ImportAlt(l, r)) => {
+            resolve_imports(l, file, stack).or_else(|_| resolve_imports(r, file, stack))
+        },
+        _ => try_map_children(expr, &mut |n| {
+            let e = resolve_imports(n, file, stack).map_err(|e| locate_at(file, &n.span, e))?;
+            Ok(Node::new(e, n.span.clone()))
+        }),
+    }
+}
+
+fn resolve_import(import: &Import, mode: ImportMode, file: &Path, stack: &mut Vec<PathBuf>) -> Result<Expr, TypeError> {
+    let import_error = |e: String| TypeError::Import(format!("{import}"), e);
+    if mode == ImportMode::Location {
+        let (alternative, location) = import2::location(import, file).map_err(import_error)?;
+        let location_t = Expr::UnionType(["Local", "Remote", "Environment"].into_iter()
+            .map(|k| (k.to_string(), Some(builtin(Builtin::Text).into())))
+            .chain([("Missing".to_string(), None)])
+            .collect());
+        return Ok(normalize(&app(Expr::Select(bx(location_t), alternative.to_string()), vec![text(location)])));
+    }
+    let path = import2::locate(import, file).map_err(import_error)?;
+
+    if stack.contains(&path) {
+        return Err(import_error("Cyclic import.".to_string()));
+    }
+    let key = (path.clone(), mode);
+    if let Some(expr) = RESOLVED.with(|map| map.borrow().get(&key).cloned()) {
+        return Ok(expr);
+    }
+
+    let headers = match import {
+        Import::Remote(_, Some(headers)) => import_headers(headers, file, stack)?,
+        _ => Vec::new(),
+    };
+    if mode == ImportMode::Bytes {
+        let bytes = import2::read_import_bytes(&path, &headers, file).map_err(import_error)?;
+        return Ok(Expr::BytesLit(bytes.to_vec()));
+    }
+    let code = import2::read_import(&path, &headers, file).map_err(import_error)?;
+    if mode == ImportMode::Text {
+        return Ok(text(code.to_string()));
+    }
+    let ast = parse2::dhall_parser().parse(&*code)
+        .map_err(|errs| TypeError::Parse(path.clone(), errs))?;
+
+    stack.push(path.clone());
+    let resolved = resolve_imports(&ast, &path, stack);
+    stack.pop();
+    let resolved = resolved?;
+
+    // imported expressions must be closed and well typed
+    type_node(&Context::new(&path), &Node::new(resolved.clone(), ast.span.clone()))?;
+    // spans of the imported file are meaningless at the import site
+    let expr = strip_spans(&resolved);
+    RESOLVED.with(|map| map.borrow_mut().insert(key, expr.clone()));
+    Ok(expr)
+}
+
+// The `using` expression of a remote import: a closed `List { mapKey : Text, mapValue : Text }`.
+fn import_headers(headers: &Node, file: &Path, stack: &mut Vec<PathBuf>) -> Result<Vec<(String, String)>, TypeError> {
+    let resolved = Node::new(resolve_imports(headers, file, stack)?, headers.span.clone());
+    let entry_t = Expr::RecordType(BTreeMap::from([
+        ("mapKey".to_string(), builtin(Builtin::Text).into()),
+        ("mapValue".to_string(), builtin(Builtin::Text).into()),
+    ]));
+    expect_node(&Context::new(file), &app(builtin(Builtin::List), vec![entry_t]), &resolved)?;
+
+    let text_of = |n: Option<&Node>| match n.map(|n| &n.expr) {
+        Some(Expr::Text(chunks)) if chunks.iter().all(|(_, e)| e.is_none()) =>
+            Some(chunks.iter().map(|(s, _)| s.as_str()).collect::<String>()),
+        Some(Expr::TextLit(s)) => Some(s.clone()),
+        _ => None,
+    };
+    let not_literal = || TypeError::Import(format!("{headers}"), "Headers must normalize to a list of text literals.".to_string());
+    match normalize(&resolved) {
+        Expr::ListLit(entries) => entries.iter().map(|entry| match &entry.expr {
+            Expr::Record(map) => text_of(map.get("mapKey")).zip(text_of(map.get("mapValue"))).ok_or_else(not_literal),
+            _ => Err(not_literal()),
+        }).collect(),
+        Expr::EmptyListLit(_) => Ok(Vec::new()),
+        _ => Err(not_literal()),
+    }
+}
+
+
+/*
+    Traversal helpers
+*/
+
+fn try_map_children<E>(expr: &Expr, f: &mut impl FnMut(&Node) -> Result<Node, E>) -> Result<Expr, E> {
+    let map_op = |op: &Op, f: &mut dyn FnMut(&Node) -> Result<Node, E>| -> Result<Op, E> {
+        Ok(match op {
+            Op::Equivalent(l, r) => Op::Equivalent(Box::new(f(l)?), Box::new(f(r)?)),
+            Op::ImportAlt(l, r) => Op::ImportAlt(Box::new(f(l)?), Box::new(f(r)?)),
+            Op::CombineTypes(l, r) => Op::CombineTypes(Box::new(f(l)?), Box::new(f(r)?)),
+            Op::Times(l, r) => Op::Times(Box::new(f(l)?), Box::new(f(r)?)),
+        })
+    };
+
+    Ok(match expr {
+        Expr::Some(e) => Expr::Some(Box::new(f(e)?)),
+        Expr::Text(chunks) => Expr::Text(chunks.iter()
+            .map(|(s, e)| Ok((s.clone(), e.as_ref().map(&mut *f).transpose()?)))
+            .collect::<Result<_, _>>()?),
+        Expr::TextLit(_) | Expr::BoolLit(_) | Expr::NaturalLit(_) | Expr::IntegerLit(_)
+        | Expr::DoubleLit(_) | Expr::BytesLit(_) | Expr::DateLit(..) | Expr::TimeLit(..) | Expr::TimeZoneLit(_)
+        | Expr::Builtin(_) | Expr::Var(_) | Expr::Import(..) | Expr::Error => expr.clone(),
+        Expr::RecordLit(items) => Expr::RecordLit(items.iter()
+            .map(|(k, v)| Ok((k.clone(), f(v)?)))
+            .collect::<Result<_, _>>()?),
+        Expr::LetIn(bindings, e) => Expr::LetIn(bindings.iter()
+            .map(|(n, t, v)| Ok((n.clone(), t.as_ref().map(&mut *f).transpose()?, f(v)?)))
+            .collect::<Result<_, _>>()?, Box::new(f(e)?)),
+        Expr::Let(n, t, v, e) => Expr::Let(n.clone(), Box::new(t.as_ref().as_ref().map(&mut *f).transpose()?), Box::new(f(v)?), Box::new(f(e)?)),
+        Expr::RecordType(map) => Expr::RecordType(map.iter()
+            .map(|(k, v)| Ok((k.clone(), f(v)?)))
+            .collect::<Result<_, _>>()?),
+        Expr::Record(map) => Expr::Record(map.iter()
+            .map(|(k, v)| Ok((k.clone(), f(v)?)))
+            .collect::<Result<_, _>>()?),
+        Expr::ListLit(items) => Expr::ListLit(items.iter().map(&mut *f).collect::<Result<_, _>>()?),
+        Expr::EmptyListLit(t) => Expr::EmptyListLit(Box::new(f(t)?)),
+        Expr::UnionType(map) => Expr::UnionType(map.iter()
+            .map(|(k, v)| Ok((k.clone(), v.as_ref().map(&mut *f).transpose()?)))
+            .collect::<Result<_, _>>()?),
+        Expr::Select(e, k) => Expr::Select(Box::new(f(e)?), k.clone()),
+        Expr::Project(e, labels) => Expr::Project(Box::new(f(e)?), labels.clone()),
+        Expr::ProjectType(e, t) => Expr::ProjectType(Box::new(f(e)?), Box::new(f(t)?)),
+        Expr::Lambda(n, t, e) => Expr::Lambda(n.clone(), Box::new(f(t)?), Box::new(f(e)?)),
+        Expr::FnType(n, t, e) => Expr::FnType(n.clone(), Box::new(f(t)?), Box::new(f(e)?)),
+        Expr::Application(vec) => Expr::Application(vec.iter().map(&mut *f).collect::<Result<_, _>>()?),
+        Expr::Merge(h, u, t) => Expr::Merge(Box::new(f(h)?), Box::new(f(u)?), t.as_ref().map(|t| f(t).map(Box::new)).transpose()?),
+        Expr::ToMap(e, t) => Expr::ToMap(Box::new(f(e)?), t.as_ref().map(|t| f(t).map(Box::new)).transpose()?),
+        Expr::ShowConstructor(e) => Expr::ShowConstructor(Box::new(f(e)?)),
+        Expr::Op(op) => Expr::Op(map_op(op, f)?),
+        Expr::Plus(l, r) => Expr::Plus(Box::new(f(l)?), Box::new(f(r)?)),
+        Expr::TextAppend(l, r) => Expr::TextAppend(Box::new(f(l)?), Box::new(f(r)?)),
+        Expr::ListAppend(l, r) => Expr::ListAppend(Box::new(f(l)?), Box::new(f(r)?)),
+        Expr::Equal(l, r) => Expr::Equal(Box::new(f(l)?), Box::new(f(r)?)),
+        Expr::NotEqual(l, r) => Expr::NotEqual(Box::new(f(l)?), Box::new(f(r)?)),
+        Expr::And(l, r) => Expr::And(Box::new(f(l)?), Box::new(f(r)?)),
+        Expr::Or(l, r) => Expr::Or(Box::new(f(l)?), Box::new(f(r)?)),
+        Expr::Combine(l, r) => Expr::Combine(Box::new(f(l)?), Box::new(f(r)?)),
+        Expr::Prefer(l, r) => Expr::Prefer(Box::new(f(l)?), Box::new(f(r)?)),
+        Expr::With(e, path, v) => Expr::With(Box::new(f(e)?), path.clone(), Box::new(f(v)?)),
+        Expr::Completion(t, r) => Expr::Completion(Box::new(f(t)?), Box::new(f(r)?)),
+        Expr::IfThenElse(c, t, e) => Expr::IfThenElse(Box::new(f(c)?), Box::new(f(t)?), Box::new(f(e)?)),
+        Expr::Annot(e, t) => Expr::Annot(Box::new(f(e)?), Box::new(f(t)?)),
+        Expr::Assert(t) => Expr::Assert(Box::new(f(t)?)),
+    })
+}
+
+// Maps `f` over all direct children, keeping the span of each child.
+fn map_children(expr: &Expr, f: &mut impl FnMut(&Expr) -> Expr) -> Expr {
+    try_map_children::<()>(expr, &mut |n| Ok(Node::new(f(n), n.span.clone()))).unwrap()
+}
+
+// Drops all source spans, e.g. of expressions that were imported from another file.
+fn strip_spans(expr: &Expr) -> Expr {
+    try_map_children::<()>(expr, &mut |n| Ok(strip_spans(n).into())).unwrap()
+}
+
+// Splits off the first binding of a let expression: `let x : t = v <rest>`
+fn split_let(expr: &Expr) -> Option<(String, Option<Node>, Node, Node)> {
+    match expr {
+        Expr::LetIn(bindings, e) => {
+            let (name, t, v) = bindings.first()?.clone();
+            let rest = if bindings.len() > 1 {
+                Node::new(Expr::LetIn(bindings[1..].to_vec(), e.clone()), bindings[1].2.span.start..e.span.end)
+            } else {
+                *e.clone()
+            };
+            Some((name, t, v, rest))
+        },
+        Expr::Let(name, t, v, e) => Some((name.clone(), *t.clone(), *v.clone(), *e.clone())),
+        _ => None,
+    }
+}
+
+
+/*
+    Shifting and substitution
+*/
+
+pub fn shift(d: isize, x: &str, m: usize, expr: &Expr) -> Expr {
+    match expr {
+        Expr::Var(Var(name, n)) => {
+            if name == x && *n >= m {
+                Expr::Var(Var(name.clone(), (*n as isize + d) as usize))
+            } else {
+                expr.clone()
+            }
+        },
+        Expr::Lambda(name, t, e) | Expr::FnType(name, t, e) => {
+            let m_inner = if name == x { m + 1 } else { m };
+            let t = respan(t, shift(d, x, m, t));
+            let e = respan(e, shift(d, x, m_inner, e));
+            if matches!(expr, Expr::Lambda(..)) { Expr::Lambda(name.clone(), t, e) } else { Expr::FnType(name.clone(), t, e) }
+        },
+        Expr::LetIn(..) | Expr::Let(..) => {
+            let (name, t, v, rest) = split_let(expr).unwrap();
+            let m_inner = if name == x { m + 1 } else { m };
+            Expr::Let(
+                name,
+                Box::new(t.map(|t| *respan(&t, shift(d, x, m, &t)))),
+                respan(&v, shift(d, x, m, &v)),
+                respan(&rest, shift(d, x, m_inner, &rest)),
+            )
+        },
+        _ => map_children(expr, &mut |e| shift(d, x, m, e)),
+    }
+}
+
+// expr[x@n := v]
+pub fn subst(x: &str, n: usize, v: &Expr, expr: &Expr) -> Expr {
+    match expr {
+        Expr::Var(Var(name, idx)) => {
+            if name == x && *idx == n { v.clone() } else { expr.clone() }
+        },
+        Expr::Lambda(name, t, e) | Expr::FnType(name, t, e) => {
+            let n_inner = if name == x { n + 1 } else { n };
+            let t = respan(t, subst(x, n, v, t));
+            let e = respan(e, subst(x, n_inner, &shift(1, name, 0, v), e));
+            if matches!(expr, Expr::Lambda(..)) { Expr::Lambda(name.clone(), t, e) } else { Expr::FnType(name.clone(), t, e) }
+        },
+        Expr::LetIn(..) | Expr::Let(..) => {
+            let (name, t, val, rest) = split_let(expr).unwrap();
+            let n_inner = if name == x { n + 1 } else { n };
+            Expr::Let(
+                name.clone(),
+                Box::new(t.map(|t| *respan(&t, subst(x, n, v, &t)))),
+                respan(&val, subst(x, n, v, &val)),
+                respan(&rest, subst(x, n_inner, &shift(1, &name, 0, v), &rest)),
+            )
+        },
+        _ => map_children(expr, &mut |e| subst(x, n, v, e)),
+    }
+}
+
+// Whether variable x@n occurs free in `expr`.
+fn free_in(x: &str, n: usize, expr: &Expr) -> bool {
+    match expr {
+        Expr::Var(Var(name, idx)) => name == x && *idx == n,
+        Expr::Lambda(name, t, e) | Expr::FnType(name, t, e) => {
+            free_in(x, n, t) || free_in(x, if name == x { n + 1 } else { n }, e)
+        },
+        Expr::LetIn(..) | Expr::Let(..) => {
+            let (name, t, v, rest) = split_let(expr).unwrap();
+            t.is_some_and(|t| free_in(x, n, &t)) || free_in(x, n, &v)
+                || free_in(x, if name == x { n + 1 } else { n }, &rest)
+        },
+        _ => {
+            let mut found = false;
+            map_children(expr, &mut |e| { found = found || free_in(x, n, e); e.clone() });
+            found
+        },
+    }
+}
+
+// (λ(x : _) → body) arg
+fn instantiate(x: &str, arg: &Expr, body: &Expr) -> Expr {
+    shift(-1, x, 0, &subst(x, 0, &shift(1, x, 0, arg), body))
+}
+
+
+/*
+    Normalization
+*/
+
+pub fn equivalent(l: &Expr, r: &Expr) -> bool {
+    alpha_normalize(&normalize(l)) == alpha_normalize(&normalize(r))
+}
+
+pub fn alpha_normalize(expr: &Expr) -> Expr {
+    match expr {
+        Expr::Lambda(name, t, e) | Expr::FnType(name, t, e) => {
+            let t = bx(alpha_normalize(t));
+            let e = if name == "_" {
+                alpha_normalize(e)
+            } else {
+                alpha_normalize(&shift(-1, name, 0, &subst(name, 0, &var("_", 0), &shift(1, "_", 0, e))))
+            };
+            if matches!(expr, Expr::Lambda(..)) { Expr::Lambda("_".to_string(), t, bx(e)) } else { Expr::FnType("_".to_string(), t, bx(e)) }
+        },
+        _ => map_children(expr, &mut alpha_normalize),
+    }
+}
+
+// The places where two normal forms differ, one entry per difference. Empty if they
+// differ as a whole, as then the expressions themselves are the best description.
+fn diff(l: &Expr, r: &Expr) -> Vec<String> {
+    fn diff_at(path: &str, l: &Expr, r: &Expr, out: &mut Vec<String>) {
+        if alpha_normalize(l) == alpha_normalize(r) {
+            return;
+        }
+        match (l, r) {
+            (Expr::Record(lm), Expr::Record(rm)) | (Expr::RecordType(lm), Expr::RecordType(rm)) => {
+                for k in lm.keys().chain(rm.keys().filter(|k| !lm.contains_key(*k))) {
+                    let field = format!("{path}.{k}");
+                    match (lm.get(k), rm.get(k)) {
+                        (Some(lv), Some(rv)) => diff_at(&field, lv, rv, out),
+                        (Some(lv), None) => out.push(format!("{field} is {lv} on the left only")),
+                        (None, Some(rv)) => out.push(format!("{field} is {rv} on the right only")),
+                        (None, None) => (),
+                    }
+                }
+            },
+            (Expr::ListLit(la), Expr::ListLit(ra)) if la.len() == ra.len() => {
+                for (i, (lv, rv)) in la.iter().zip(ra).enumerate() {
+                    diff_at(&format!("{path}[{i}]"), lv, rv, out);
+                }
+            },
+            (Expr::Some(lv), Expr::Some(rv)) => diff_at(&format!("{path}.?"), lv, rv, out),
+            _ => out.push(format!("{path} is {l} on the left but {r} on the right")),
+        }
+    }
+
+    let mut out = Vec::new();
+    match (l, r) {
+        (Expr::Record(_), Expr::Record(_)) | (Expr::RecordType(_), Expr::RecordType(_))
+        | (Expr::ListLit(_), Expr::ListLit(_)) | (Expr::Some(_), Expr::Some(_)) => diff_at("", l, r, &mut out),
+        _ => (),
+    }
+    out
+}
+
+// Record literal fields with duplicate labels are merged with `/\`.
+fn desugar_record_lit(items: &[(String, Node)]) -> BTreeMap<String, Node> {
+    let mut map: BTreeMap<String, Node> = BTreeMap::new();
+    for (k, v) in items {
+        let v = if let Some(prev) = map.remove(k) {
+            let span = prev.span.start..v.span.end;
+            Node::new(Expr::Combine(Box::new(prev), Box::new(v.clone())), span)
+        } else { v.clone() };
+        map.insert(k.clone(), v);
+    }
+    map
+}
+
+fn text_literal(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::TextLit(s) => Some(s.clone()),
+        Expr::Text(chunks) => {
+            let mut r = String::new();
+            for (s, e) in chunks {
+                if e.is_some() { return None; }
+                r.push_str(s);
+            }
+            Some(r)
+        },
+        _ => None,
+    }
+}
+
+fn list_items(expr: &Expr) -> Option<Vec<Expr>> {
+    match expr {
+        Expr::ListLit(items) => Some(items.iter().map(|n| n.expr.clone()).collect()),
+        Expr::EmptyListLit(_) => Some(Vec::new()),
+        _ => None,
+    }
+}
+
+fn list_element_type(t: &Expr) -> Option<Expr> {
+    match t {
+        Expr::Application(vec) if vec.len() == 2 && vec[0].expr == builtin(Builtin::List) => Some(vec[1].expr.clone()),
+        _ => None,
+    }
+}
+
+fn normalize_text(chunks: &[(String, Option<Node>)]) -> Expr {
+    let mut result: Vec<(String, Option<Node>)> = Vec::new();
+    let mut s = String::new();
+    for (prefix, e) in chunks {
+        s.push_str(prefix);
+        if let Some(e) = e {
+            match normalize(e) {
+                Expr::Text(inner) => {
+                    for (prefix, e) in inner {
+                        s.push_str(&prefix);
+                        if let Some(e) = e {
+                            result.push((std::mem::take(&mut s), Some(e)));
+                        }
+                    }
+                },
+                e => result.push((std::mem::take(&mut s), Some(e.into()))),
+            }
+        }
+    }
+    if !s.is_empty() { result.push((s, None)) }
+
+    // "${t}" is equivalent to t
+    if result.len() == 1 && result[0].0.is_empty() {
+        if let Some(e) = &result[0].1 {
+            return e.expr.clone();
+        }
+    }
+    Expr::Text(result)
+}
+
+// The alternative a normalized union or Optional value was built with, and its argument.
+fn alternative_of(e: &Expr) -> Option<(String, Option<Expr>)> {
+    let constructor = |e: &Expr| match e {
+        Expr::Select(t, k) if matches!(t.expr, Expr::UnionType(_)) => Some(k.clone()),
+        _ => None,
+    };
+    match e {
+        Expr::Application(vec) if vec.len() == 2 && vec[0].expr == builtin(Builtin::None) => Some(("None".to_string(), None)),
+        Expr::Application(vec) if vec.len() == 2 => constructor(&vec[0]).map(|k| (k, Some(vec[1].expr.clone()))),
+        Expr::Some(v) => Some(("Some".to_string(), Some(v.expr.clone()))),
+        e => constructor(e).map(|k| (k, None)),
+    }
+}
+
+// `T::r` fills the fields missing from `r` with those of `T.default`.
+fn completion(t: &Node, r: &Node) -> Expr {
+    let default = Node::new(Expr::Select(Box::new(t.clone()), "default".to_string()), t.span.clone());
+    Expr::Prefer(Box::new(default), Box::new(r.clone()))
+}
+
+// Normal form of `e with path = v`. Fields missing from a record literal are created,
+// while an abstract `e` stays a with expression.
+fn with(e: Expr, path: &[PathComponent], v: Expr) -> Expr {
+    let Some((first, rest)) = path.split_first() else {
+        return v;
+    };
+    match (first, e) {
+        (PathComponent::Label(k), Expr::Record(mut map)) => {
+            let inner = map.remove(k).map_or_else(|| Expr::Record(BTreeMap::new()), |n| n.expr);
+            map.insert(k.clone(), with(inner, rest, v).into());
+            Expr::Record(map)
+        },
+        (PathComponent::DescendOptional, Expr::Some(inner)) => Expr::Some(bx(with(inner.expr, rest, v))),
+        (PathComponent::DescendOptional, e @ Expr::Application(_)) if alternative_of(&e).is_some_and(|(k, _)| k == "None") => e,
+        (_, e) => Expr::With(bx(e), path.to_vec(), bx(v)),
+    }
+}
+
+pub fn normalize(expr: &Expr) -> Expr {
+    match expr {
+        Expr::BoolLit(_) | Expr::NaturalLit(_) | Expr::IntegerLit(_) | Expr::DoubleLit(_)
+        | Expr::BytesLit(_) | Expr::DateLit(..) | Expr::TimeLit(..) | Expr::TimeZoneLit(_)
+        | Expr::Builtin(_) | Expr::Var(_) | Expr::Import(..) | Expr::Error => expr.clone(),
+        Expr::TextLit(s) => text(s.clone()),
+        Expr::Text(chunks) => normalize_text(chunks),
+        Expr::TextAppend(l, r) => {
+            normalize_text(&[("".to_string(), Some(*l.clone())), ("".to_string(), Some(*r.clone()))])
+        },
+        Expr::Some(e) => Expr::Some(bx(normalize(e))),
+        Expr::Lambda(..) | Expr::FnType(..) => map_children(expr, &mut normalize),
+        Expr::LetIn(..) | Expr::Let(..) => {
+            let (name, _, v, rest) = split_let(expr).unwrap();
+            normalize(&instantiate(&name, &v, &rest))
+        },
+        Expr::Annot(e, _) => normalize(e),
+        Expr::Application(vec) => {
+            let mut f = normalize(&vec[0]);
+            for a in &vec[1..] {
+                f = apply(f, normalize(a));
+            }
+            f
+        },
+
+        // Records
+        Expr::RecordLit(items) => Expr::Record(desugar_record_lit(items).iter()
+            .map(|(k, v)| (k.clone(), normalize(v).into())).collect()),
+        Expr::Record(_) | Expr::RecordType(_) | Expr::UnionType(_) => map_children(expr, &mut normalize),
+        Expr::Select(e, k) => {
+            let e = normalize(e);
+            select(e, k)
+        },
+        Expr::Project(e, labels) => project(normalize(e), labels),
+        Expr::ProjectType(e, t) => match normalize(t) {
+            Expr::RecordType(map) => project(normalize(e), &map.into_keys().collect::<Vec<_>>()),
+            t => Expr::ProjectType(bx(normalize(e)), bx(t)),
+        },
+        Expr::Merge(h, u, t) => {
+            let handlers = normalize(h);
+            let union = normalize(u);
+            match (&handlers, alternative_of(&union)) {
+                (Expr::Record(map), Some((k, v))) if map.contains_key(&k) => {
+                    let handler = map[&k].expr.clone();
+                    match v {
+                        Some(v) => apply(handler, v),
+                        None => handler,
+                    }
+                },
+                _ => Expr::Merge(bx(handlers), bx(union), t.as_ref().map(|t| bx(normalize(t)))),
+            }
+        },
+        Expr::ShowConstructor(e) => {
+            let e = normalize(e);
+            match alternative_of(&e) {
+                Some((k, _)) => text(k),
+                None => Expr::ShowConstructor(bx(e)),
+            }
+        },
+        Expr::ToMap(e, t) => match (normalize(e), t) {
+            (Expr::Record(map), Some(t)) if map.is_empty() => Expr::EmptyListLit(bx(normalize(t))),
+            (Expr::Record(map), _) if !map.is_empty() => Expr::ListLit(map.into_iter()
+                .map(|(k, v)| Expr::Record(BTreeMap::from([
+                    ("mapKey".to_string(), text(k).into()),
+                    ("mapValue".to_string(), v),
+                ])).into())
+                .collect()),
+            (e, t) => Expr::ToMap(bx(e), t.as_ref().map(|t| bx(normalize(t)))),
+        },
+        Expr::Combine(l, r) => combine(normalize(l), normalize(r)),
+        Expr::With(e, path, v) => with(normalize(e), path, normalize(v)),
+        Expr::Completion(t, r) => normalize(&completion(t, r)),
+        Expr::Op(Op::CombineTypes(l, r)) => combine_types(normalize(l), normalize(r)),
+        Expr::Prefer(l, r) => {
+            match (normalize(l), normalize(r)) {
+                (Expr::Record(l), r) if l.is_empty() => r,
+                (l, Expr::Record(r)) if r.is_empty() => l,
+                (Expr::Record(mut l), Expr::Record(r)) => {
+                    l.extend(r);
+                    Expr::Record(l)
+                },
+                (l, r) if l == r => l,
+                (l, r) => Expr::Prefer(bx(l), bx(r)),
+            }
+        },
+
+        // Lists
+        Expr::ListLit(items) => Expr::ListLit(items.iter().map(|e| normalize(e).into()).collect()),
+        Expr::EmptyListLit(t) => Expr::EmptyListLit(bx(normalize(t))),
+        Expr::ListAppend(l, r) => {
+            match (normalize(l), normalize(r)) {
+                (Expr::EmptyListLit(_), r) => r,
+                (l, Expr::EmptyListLit(_)) => l,
+                (Expr::ListLit(mut l), Expr::ListLit(r)) => {
+                    l.extend(r);
+                    Expr::ListLit(l)
+                },
+                (l, r) => Expr::ListAppend(bx(l), bx(r)),
+            }
+        },
+
+        // Arithmetic
+        Expr::Plus(l, r) => {
+            match (normalize(l), normalize(r)) {
+                (Expr::NaturalLit(l), Expr::NaturalLit(r)) => Expr::NaturalLit(l + r),
+                (Expr::NaturalLit(l), r) if l.is_zero() => r,
+                (l, Expr::NaturalLit(r)) if r.is_zero() => l,
+                (l, r) => Expr::Plus(bx(l), bx(r)),
+            }
+        },
+        Expr::Op(Op::Times(l, r)) => {
+            match (normalize(l), normalize(r)) {
+                (Expr::NaturalLit(l), Expr::NaturalLit(r)) => Expr::NaturalLit(l * r),
+                (Expr::NaturalLit(n), _) | (_, Expr::NaturalLit(n)) if n.is_zero() => Expr::NaturalLit(n),
+                (Expr::NaturalLit(l), r) if l.is_one() => r,
+                (l, Expr::NaturalLit(r)) if r.is_one() => l,
+                (l, r) => Expr::Op(Op::Times(bx(l), bx(r))),
+            }
+        },
+
+        // Logic
+        Expr::Or(l, r) => {
+            match (normalize(l), normalize(r)) {
+                (Expr::BoolLit(true), _) | (_, Expr::BoolLit(true)) => Expr::BoolLit(true),
+                (Expr::BoolLit(false), r) => r,
+                (l, Expr::BoolLit(false)) => l,
+                (l, r) if l == r => l,
+                (l, r) => Expr::Or(bx(l), bx(r)),
+            }
+        },
+        Expr::And(l, r) => {
+            match (normalize(l), normalize(r)) {
+                (Expr::BoolLit(false), _) | (_, Expr::BoolLit(false)) => Expr::BoolLit(false),
+                (Expr::BoolLit(true), r) => r,
+                (l, Expr::BoolLit(true)) => l,
+                (l, r) if l == r => l,
+                (l, r) => Expr::And(bx(l), bx(r)),
+            }
+        },
+        Expr::Equal(l, r) => {
+            match (normalize(l), normalize(r)) {
+                (Expr::BoolLit(true), r) => r,
+                (l, Expr::BoolLit(true)) => l,
+                (l, r) if l == r => Expr::BoolLit(true),
+                (l, r) => Expr::Equal(bx(l), bx(r)),
+            }
+        },
+        Expr::NotEqual(l, r) => {
+            match (normalize(l), normalize(r)) {
+                (Expr::BoolLit(false), r) => r,
+                (l, Expr::BoolLit(false)) => l,
+                (l, r) if l == r => Expr::BoolLit(false),
+                (l, r) => Expr::NotEqual(bx(l), bx(r)),
+            }
+        },
+        Expr::IfThenElse(c, t, e) => {
+            match (normalize(c), normalize(t), normalize(e)) {
+                (Expr::BoolLit(true), t, _) => t,
+                (Expr::BoolLit(false), _, e) => e,
+                (c, Expr::BoolLit(true), Expr::BoolLit(false)) => c,
+                (_, t, e) if t == e => t,
+                (c, t, e) => Expr::IfThenElse(bx(c), bx(t), bx(e)),
+            }
+        },
+
+        Expr::Op(Op::Equivalent(l, r)) => Expr::Op(Op::Equivalent(bx(normalize(l)), bx(normalize(r)))),
+        Expr::Assert(t) => Expr::Assert(bx(normalize(t))),
+        Expr::Op(Op::ImportAlt(l, _)) => normalize(l),
+    }
+}
+
+fn select(e: Expr, k: &str) -> Expr {
+    match e {
+        Expr::Record(mut map) if map.contains_key(k) => map.remove(k).unwrap().expr,
+        Expr::Prefer(l, r) => match r.expr {
+            Expr::Record(mut map) => {
+                if let Some(v) = map.remove(k) { v.expr } else { select(l.expr, k) }
+            },
+            r => Expr::Select(bx(Expr::Prefer(l, bx(r))), k.to_string()),
+        },
+        e => Expr::Select(bx(e), k.to_string()),
+    }
+}
+
+fn project(e: Expr, labels: &[String]) -> Expr {
+    let mut labels = labels.to_vec();
+    labels.sort();
+    labels.dedup();
+    match e {
+        _ if labels.is_empty() => Expr::Record(BTreeMap::new()),
+        Expr::Record(mut map) => Expr::Record(labels.into_iter()
+            .filter_map(|k| map.remove_entry(&k)).collect()),
+        Expr::Project(e, _) => project(e.expr, &labels),
+        e => Expr::Project(bx(e), labels),
+    }
+}
+
+fn combine(l: Expr, r: Expr) -> Expr {
+    match (l, r) {
+        (Expr::Record(l), r) if l.is_empty() => r,
+        (l, Expr::Record(r)) if r.is_empty() => l,
+        (Expr::Record(mut l), Expr::Record(r)) => {
+            for (k, v) in r {
+                let v = if let Some(prev) = l.remove(&k) { combine(prev.expr, v.expr).into() } else { v };
+                l.insert(k, v);
+            }
+            Expr::Record(l)
+        },
+        (l, r) => Expr::Combine(bx(l), bx(r)),
+    }
+}
+
+fn combine_types(l: Expr, r: Expr) -> Expr {
+    match (l, r) {
+        (Expr::RecordType(l), r) if l.is_empty() => r,
+        (l, Expr::RecordType(r)) if r.is_empty() => l,
+        (Expr::RecordType(mut l), Expr::RecordType(r)) => {
+            for (k, v) in r {
+                let v = if let Some(prev) = l.remove(&k) { combine_types(prev.expr, v.expr).into() } else { v };
+                l.insert(k, v);
+            }
+            Expr::RecordType(l)
+        },
+        (l, r) => Expr::Op(Op::CombineTypes(bx(l), bx(r))),
+    }
+}
+
+// Apply normalized function `f` to normalized argument `a`.
+fn apply(f: Expr, a: Expr) -> Expr {
+    match f {
+        Expr::Lambda(x, _, body) => normalize(&instantiate(&x, &a, &body)),
+        Expr::Application(vec) => {
+            let mut vec: Vec<Expr> = vec.into_iter().map(|n| n.expr).collect();
+            vec.push(a);
+            reduce_builtin(vec)
+        },
+        f => reduce_builtin(vec![f, a]),
+    }
+}
+
+// Recognizes normalized functions that add a constant k to their argument, returning k. The
+// function is applied to a fresh variable, so `\(x : Natural) -> let one = 1 in x + one` is found too.
+fn increment(succ: &Expr) -> Option<BigUint> {
+    let x = var("_", 0);
+    match apply(shift(1, "_", 0, succ), x.clone()) {
+        Expr::Plus(l, r) => match (&l.expr, &r.expr) {
+            (Expr::NaturalLit(k), e) | (e, Expr::NaturalLit(k)) if *e == x => Some(k.clone()),
+            _ => None,
+        },
+        e if e == x => Some(BigUint::zero()),
+        _ => None,
+    }
+}
+
+fn reduce_builtin(vec: Vec<Expr>) -> Expr {
+    let Expr::Builtin(b) = &vec[0] else { return app(vec[0].clone(), vec[1..].to_vec()) };
+    let args = &vec[1..];
+
+    let r = match (b, args) {
+        (Builtin::NaturalBuild, [g]) => {
+            let succ = Expr::Lambda("x".to_string(), bx(builtin(Builtin::Natural)),
+                bx(Expr::Plus(bx(var("x", 0)), bx(Expr::NaturalLit(BigUint::one())))));
+            let r = apply(g.clone(), builtin(Builtin::Natural));
+            let r = apply(r, succ);
+            Some(apply(r, Expr::NaturalLit(BigUint::zero())))
+        },
+        (Builtin::NaturalFold, [Expr::NaturalLit(n), _, succ, zero]) => match (increment(succ), zero) {
+            // folding `\(x : Natural) -> x + k` does not need to apply it n times
+            (Some(k), Expr::NaturalLit(zero)) => Some(Expr::NaturalLit(n * k + zero)),
+            _ => {
+                let mut acc = zero.clone();
+                let mut i = BigUint::zero();
+                while i < *n {
+                    let next = apply(succ.clone(), acc.clone());
+                    if next == acc {
+                        // succ has reached a fixed point, the remaining iterations change nothing
+                        break;
+                    }
+                    acc = next;
+                    i += 1u32;
+                }
+                Some(acc)
+            },
+        },
+        (Builtin::NaturalIsZero, [Expr::NaturalLit(n)]) => Some(Expr::BoolLit(n.is_zero())),
+        (Builtin::NaturalEven, [Expr::NaturalLit(n)]) => Some(Expr::BoolLit(!n.bit(0))),
+        (Builtin::NaturalOdd, [Expr::NaturalLit(n)]) => Some(Expr::BoolLit(n.bit(0))),
+        (Builtin::NaturalToInteger, [Expr::NaturalLit(n)]) => Some(Expr::IntegerLit(BigInt::from(n.clone()))),
+        (Builtin::NaturalShow, [Expr::NaturalLit(n)]) => Some(text(format!("{n}"))),
+        (Builtin::NaturalSubtract, [Expr::NaturalLit(a), Expr::NaturalLit(b)]) =>
+            Some(Expr::NaturalLit(if b > a { b - a } else { BigUint::zero() })),
+        (Builtin::NaturalSubtract, [Expr::NaturalLit(a), y]) if a.is_zero() => Some(y.clone()),
+        (Builtin::NaturalSubtract, [_, Expr::NaturalLit(b)]) if b.is_zero() => Some(Expr::NaturalLit(BigUint::zero())),
+        (Builtin::NaturalSubtract, [x, y]) if x == y => Some(Expr::NaturalLit(BigUint::zero())),
+        (Builtin::IntegerToDouble, [Expr::IntegerLit(i)]) => Some(Expr::DoubleLit(i.into())),
+        (Builtin::IntegerShow, [e @ Expr::IntegerLit(_)]) => Some(text(e.to_string())),
+        (Builtin::IntegerNegate, [Expr::IntegerLit(i)]) => Some(Expr::IntegerLit(-i)),
+        (Builtin::IntegerClamp, [Expr::IntegerLit(i)]) => Some(Expr::NaturalLit(i.to_biguint().unwrap_or_default())),
+        (Builtin::DoubleShow, [Expr::DoubleLit(d)]) => Some(text(Expr::DoubleLit(*d).to_string())),
+        (Builtin::ListBuild, [a, g]) => {
+            let list_a = app(builtin(Builtin::List), vec![a.clone()]);
+            let list_a1 = shift(1, "a", 0, &list_a);
+            let cons = Expr::Lambda("a".to_string(), bx(a.clone()),
+                bx(Expr::Lambda("as".to_string(), bx(list_a1),
+                    bx(Expr::ListAppend(bx(Expr::ListLit(vec![var("a", 0).into()])), bx(var("as", 0)))))));
+            let r = apply(g.clone(), list_a.clone());
+            let r = apply(r, cons);
+            Some(apply(r, Expr::EmptyListLit(bx(list_a))))
+        },
+        (Builtin::ListFold, [_, list, _, cons, nil]) => list_items(list).map(|items| {
+            let mut acc = nil.clone();
+            for item in items.into_iter().rev() {
+                acc = apply(apply(cons.clone(), item), acc);
+            }
+            acc
+        }),
+        (Builtin::ListLength, [_, list]) => list_items(list).map(|items| Expr::NaturalLit(items.len().into())),
+        (Builtin::ListHead, [a, list]) => list_items(list).map(|items| match items.first() {
+            Some(e) => Expr::Some(bx(e.clone())),
+            None => app(builtin(Builtin::None), vec![a.clone()]),
+        }),
+        (Builtin::ListLast, [a, list]) => list_items(list).map(|items| match items.last() {
+            Some(e) => Expr::Some(bx(e.clone())),
+            None => app(builtin(Builtin::None), vec![a.clone()]),
+        }),
+        (Builtin::ListIndexed, [a, list]) => list_items(list).map(|items| {
+            if items.is_empty() {
+                let mut t = BTreeMap::new();
+                t.insert("index".to_string(), builtin(Builtin::Natural).into());
+                t.insert("value".to_string(), a.clone().into());
+                Expr::EmptyListLit(bx(app(builtin(Builtin::List), vec![Expr::RecordType(t)])))
+            } else {
+                Expr::ListLit(items.into_iter().enumerate().map(|(i, e)| {
+                    let mut r = BTreeMap::new();
+                    r.insert("index".to_string(), Expr::NaturalLit(i.into()).into());
+                    r.insert("value".to_string(), e.into());
+                    Expr::Record(r).into()
+                }).collect())
+            }
+        }),
+        (Builtin::ListReverse, [_, list]) => match list {
+            Expr::ListLit(items) => Some(Expr::ListLit(items.iter().rev().cloned().collect())),
+            Expr::EmptyListLit(_) => Some(list.clone()),
+            _ => None,
+        },
+        (Builtin::TextShow, [t]) => text_literal(t).map(|s| text(format!("\"{}\"", crate::ast::escape_text(&s)))),
+        (Builtin::TextReplace, [needle, replacement, haystack]) => {
+            match (text_literal(needle), text_literal(haystack)) {
+                (Some(n), _) if n.is_empty() => Some(haystack.clone()),
+                // the replacement may be abstract, it is interpolated between the pieces of the haystack
+                (Some(n), Some(h)) => {
+                    let mut chunks = Vec::new();
+                    for (i, piece) in h.split(n.as_str()).enumerate() {
+                        if i > 0 {
+                            chunks.push((String::new(), Some(replacement.clone().into())));
+                        }
+                        chunks.push((piece.to_string(), None));
+                    }
+                    Some(normalize_text(&chunks))
+                },
+                _ => None,
+            }
+        },
+        (Builtin::DateShow, [e @ Expr::DateLit(..)])
+        | (Builtin::TimeShow, [e @ Expr::TimeLit(..)])
+        | (Builtin::TimeZoneShow, [e @ Expr::TimeZoneLit(_)]) => Some(text(e.to_string())),
+        _ => None,
+    };
+
+    r.unwrap_or_else(|| app(vec[0].clone(), vec[1..].to_vec()))
+}
+
+
+/*
+    Type inference
+*/
+
+// Typing context. Types stored in the context are always valid in the current scope and
+// never refer to let bound variables. `file` is the source of the expression being checked
+// and is used to locate errors.
+#[derive(Debug, Clone, Default)]
+struct Context {
+    vars: Vec<(String, Expr, Option<Rc<Definition>>)>,
+    file: Option<Rc<PathBuf>>,
+}
+
+// The value of a let binding, valid in the context before the binding. It is only
+// normalized once a type depends on it, so that checking does not evaluate the program.
+#[derive(Debug)]
+struct Definition {
+    value: Expr,
+    normal: OnceCell<Expr>,
+}
+
+impl Context {
+    fn new(file: &Path) -> Context {
+        Context { vars: Vec::new(), file: Some(Rc::new(file.to_path_buf())) }
+    }
+
+    fn insert(&self, name: &str, t: Expr) -> Context {
+        self.bind(name, t, None)
+    }
+
+    // Binds `name : t` to `value`, e.g. for `let name : t = value`.
+    fn define(&self, name: &str, t: Expr, value: Expr) -> Context {
+        self.bind(name, t, Some(Rc::new(Definition { value, normal: OnceCell::new() })))
+    }
+
+    fn bind(&self, name: &str, t: Expr, def: Option<Rc<Definition>>) -> Context {
+        let mut vars: Vec<_> = self.vars.iter()
+            .map(|(n, t, def)| (n.clone(), shift(1, name, 0, t), def.clone()))
+            .collect();
+        vars.push((name.to_string(), shift(1, name, 0, &t), def));
+        Context { vars, file: self.file.clone() }
+    }
+
+    // Normalizes `e`, substituting the let bound variables it refers to.
+    fn normalize(&self, e: &Expr) -> Expr {
+        normalize_in(&self.vars, e)
+    }
+
+    fn lookup(&self, var: &Var) -> Option<&Expr> {
+        let mut idx = var.1;
+        for (name, t, _) in self.vars.iter().rev() {
+            if name == &var.0 {
+                if idx == 0 { return Some(t); }
+                idx -= 1;
+            }
+        }
+        None
+    }
+}
+
+fn normalize_in(vars: &[(String, Expr, Option<Rc<Definition>>)], e: &Expr) -> Expr {
+    let mut e = e.clone();
+    for (j, (name, _, def)) in vars.iter().enumerate().rev() {
+        let Some(def) = def else { continue };
+        let idx = vars[j + 1..].iter().filter(|(n, ..)| n == name).count();
+        if !free_in(name, idx, &e) {
+            continue;
+        }
+        // the value is normalized where it was defined and then shifted over the later bindings
+        let value = def.normal.get_or_init(|| normalize_in(&vars[..j], &def.value));
+        let value = vars[j..].iter().fold(value.clone(), |v, (n, ..)| shift(1, n, 0, &v));
+        e = subst(name, idx, &value, &e);
+    }
+    normalize(&e)
+}
+
+fn const_rank(b: &Builtin) -> Option<u8> {
+    match b {
+        Builtin::Type => Some(0),
+        Builtin::Kind => Some(1),
+        Builtin::Sort => Some(2),
+        _ => None,
+    }
+}
+
+fn as_const(e: &Expr) -> Option<Builtin> {
+    match e {
+        Expr::Builtin(b) if const_rank(b).is_some() => Some(b.clone()),
+        _ => None,
+    }
+}
+
+fn max_const(l: Builtin, r: Builtin) -> Builtin {
+    if const_rank(&l) >= const_rank(&r) { l } else { r }
+}
+
+fn expect(expected: &Expr, found: &Expr) -> Result<(), TypeError> {
+    if equivalent(expected, found) {
+        Ok(())
+    } else {
+        Err(TypeError::Expected { expected: expected.to_string(), found: found.clone() })
+    }
+}
+
+fn builtin_type(b: &Builtin) -> Result<Expr, TypeError> {
+    if let Some(t) = BUILTIN_TYPES.with(|map| map.borrow().get(b).cloned()) {
+        return Ok(t);
+    }
+    let source = match b {
+        Builtin::NaturalFold => "Natural → ∀(natural : Type) → ∀(succ : natural → natural) → ∀(zero : natural) → natural",
+        Builtin::NaturalBuild => "(∀(natural : Type) → ∀(succ : natural → natural) → ∀(zero : natural) → natural) → Natural",
+        Builtin::NaturalIsZero | Builtin::NaturalEven | Builtin::NaturalOdd => "Natural → Bool",
+        Builtin::NaturalToInteger => "Natural → Integer",
+        Builtin::NaturalShow => "Natural → Text",
+        Builtin::NaturalSubtract => "Natural → Natural → Natural",
+        Builtin::IntegerToDouble => "Integer → Double",
+        Builtin::IntegerShow => "Integer → Text",
+        Builtin::IntegerNegate => "Integer → Integer",
+        Builtin::IntegerClamp => "Integer → Natural",
+        Builtin::DoubleShow => "Double → Text",
+        Builtin::ListBuild => "∀(a : Type) → (∀(list : Type) → ∀(cons : a → list → list) → ∀(nil : list) → list) → List a",
+        Builtin::ListFold => "∀(a : Type) → List a → ∀(list : Type) → ∀(cons : a → list → list) → ∀(nil : list) → list",
+        Builtin::ListLength => "∀(a : Type) → List a → Natural",
+        Builtin::ListHead | Builtin::ListLast => "∀(a : Type) → List a → Optional a",
+        Builtin::ListIndexed => "∀(a : Type) → List a → List { index : Natural, value : a }",
+        Builtin::ListReverse => "∀(a : Type) → List a → List a",
+        Builtin::TextShow => "Text → Text",
+        Builtin::TextReplace => "∀(needle : Text) → ∀(replacement : Text) → ∀(haystack : Text) → Text",
+        Builtin::DateShow => "Date → Text",
+        Builtin::TimeShow => "Time → Text",
+        Builtin::TimeZoneShow => "TimeZone → Text",
+        Builtin::Bool | Builtin::Natural | Builtin::Integer | Builtin::Double | Builtin::Text
+        | Builtin::Bytes | Builtin::Date | Builtin::Time | Builtin::TimeZone => "Type",
+        Builtin::List | Builtin::Optional => "Type → Type",
+        Builtin::None => "∀(A : Type) → Optional A",
+        Builtin::True | Builtin::False => "Bool",
+        Builtin::Type => "Kind",
+        Builtin::Kind => "Sort",
+        Builtin::Sort => return Err(TypeError::Untyped(builtin(Builtin::Sort))),
+        Builtin::Some => return Err(TypeError::InternalBug("Some is not a builtin value.".to_string())),
+    };
+    let t = parse2::dhall_parser().parse(source)
+        .map_err(|e| TypeError::InternalBug(format!("Could not parse type of builtin {b}: {e:?}")))?;
+    let t = normalize(&t);
+    BUILTIN_TYPES.with(|map| map.borrow_mut().insert(b.clone(), t.clone()));
+    Ok(t)
+}
+
+// Returns the (normalized) universe of type expression `t`.
+fn universe(ctx: &Context, t: &Expr) -> Result<Option<Builtin>, TypeError> {
+    Ok(as_const(&type_with(ctx, t)?))
+}
+
+fn record_fields_type(ctx: &Context, fields: &BTreeMap<String, Node>) -> Result<Expr, TypeError> {
+    let mut types = BTreeMap::new();
+    for (k, v) in fields {
+        let t = type_node(ctx, v)?;
+        if universe(ctx, &t)?.is_none() {
+            return Err(TypeError::InvalidFieldType(t));
+        }
+        types.insert(k.clone(), t.into());
+    }
+    Ok(Expr::RecordType(types))
+}
+
+// Type of `e with path = v`, given the types of `e` and `v`.
+fn with_type(t: Expr, path: &[PathComponent], v_type: Expr) -> Result<Expr, TypeError> {
+    let Some((first, rest)) = path.split_first() else {
+        return Ok(v_type);
+    };
+    match (first, t) {
+        (PathComponent::Label(k), Expr::RecordType(mut map)) => {
+            let inner = map.remove(k).map_or_else(|| Expr::RecordType(BTreeMap::new()), |n| n.expr);
+            map.insert(k.clone(), with_type(inner, rest, v_type)?.into());
+            Ok(Expr::RecordType(map))
+        },
+        (PathComponent::DescendOptional, Expr::Application(vec)) if vec.len() == 2 && vec[0].expr == builtin(Builtin::Optional) => {
+            // the content of an Optional can only be replaced by a value of the same type
+            let inner = vec[1].expr.clone();
+            let updated = with_type(inner.clone(), rest, v_type)?;
+            if !equivalent(&inner, &updated) {
+                return Err(TypeError::WithOptionalMismatch { expected: Box::new(inner), found: Box::new(updated) });
+            }
+            Ok(Expr::Application(vec))
+        },
+        (PathComponent::Label(k), t) => Err(TypeError::Expected { expected: format!("a record with field {k} to update"), found: t }),
+        (PathComponent::DescendOptional, t) => Err(TypeError::Expected { expected: "an Optional to update".to_string(), found: t }),
+    }
+}
+
+// Merge two record types recursively, failing on colliding non-record fields.
+fn merge_record_types(l: &BTreeMap<String, Node>, r: &BTreeMap<String, Node>) -> Result<BTreeMap<String, Node>, TypeError> {
+    let mut result = l.clone();
+    for (k, rt) in r {
+        let t = match (result.remove(k).map(|lt| lt.expr), &rt.expr) {
+            (None, _) => rt.clone(),
+            (Some(Expr::RecordType(lt)), Expr::RecordType(rt)) => Expr::RecordType(merge_record_types(&lt, rt)?).into(),
+            (Some(_), _) => return Err(TypeError::FieldCollision(k.clone())),
+        };
+        result.insert(k.clone(), t);
+    }
+    Ok(result)
+}
+
+fn record_type_of(ctx: &Context, e: &Node) -> Result<BTreeMap<String, Node>, TypeError> {
+    match type_node(ctx, e)? {
+        Expr::RecordType(map) => Ok(map),
+        t => Err(TypeError::Expected { expected: "a record".to_string(), found: t }),
+    }
+}
+
+// Attaches the location of `node` to an error that has none yet.
+fn locate(ctx: &Context, node: &Node, err: TypeError) -> TypeError {
+    match &ctx.file {
+        Some(file) => locate_at(file, &node.span, err),
+        None => err,
+    }
+}
+
+fn locate_at(file: &Path, span: &Span, err: TypeError) -> TypeError {
+    match err {
+        TypeError::At(..) | TypeError::Parse(..) => err,
+        err if !span.is_empty() => TypeError::At(Location::new(file.to_path_buf(), span.clone()), Box::new(err)),
+        err => err,
+    }
+}
+
+// Infers the type of `node`, attaching its location to errors.
+fn type_node(ctx: &Context, node: &Node) -> Result<Expr, TypeError> {
+    type_with(ctx, node).map_err(|e| locate(ctx, node, e))
+}
+
+// Checks that `node` has type `expected`, reporting a mismatch at `node`.
+fn expect_node(ctx: &Context, expected: &Expr, node: &Node) -> Result<(), TypeError> {
+    let found = type_node(ctx, node)?;
+    expect(expected, &found).map_err(|e| locate(ctx, node, e))
+}
+
+fn type_with(ctx: &Context, expr: &Expr) -> Result<Expr, TypeError> {
+    let bool_t = builtin(Builtin::Bool);
+    let natural_t = builtin(Builtin::Natural);
+    let text_t = builtin(Builtin::Text);
+
+    match expr {
+        Expr::Var(v) => ctx.lookup(v).cloned()
+            .ok_or_else(|| TypeError::UnboundVariable(Expr::Var(v.clone()).to_string())),
+        Expr::Builtin(b) => builtin_type(b),
+        Expr::BoolLit(_) => Ok(bool_t),
+        Expr::NaturalLit(_) => Ok(natural_t),
+        Expr::IntegerLit(_) => Ok(builtin(Builtin::Integer)),
+        Expr::DoubleLit(_) => Ok(builtin(Builtin::Double)),
+        Expr::BytesLit(_) => Ok(builtin(Builtin::Bytes)),
+        Expr::DateLit(..) => Ok(builtin(Builtin::Date)),
+        Expr::TimeLit(..) => Ok(builtin(Builtin::Time)),
+        Expr::TimeZoneLit(_) => Ok(builtin(Builtin::TimeZone)),
+        Expr::TextLit(_) => Ok(text_t),
+        Expr::Text(chunks) => {
+            for (_, e) in chunks {
+                if let Some(e) = e {
+                    expect_node(ctx, &text_t, e)?;
+                }
+            }
+            Ok(text_t)
+        },
+
+        Expr::Lambda(x, t, body) => {
+            if as_const(&type_node(ctx, t)?).is_none() {
+                return Err(TypeError::InvalidInputType(t.expr.clone()));
+            }
+            let t = ctx.normalize(t);
+            let body_t = type_node(&ctx.insert(x, t.clone()), body)?;
+            let fn_type = Expr::FnType(x.clone(), bx(t), bx(body_t));
+            type_with(ctx, &fn_type)?;
+            Ok(fn_type)
+        },
+        Expr::FnType(x, t, body) => {
+            let Some(input) = as_const(&type_node(ctx, t)?) else {
+                return Err(TypeError::InvalidInputType(t.expr.clone()));
+            };
+            let Some(output) = as_const(&type_node(&ctx.insert(x, ctx.normalize(t)), body)?) else {
+                return Err(TypeError::InvalidOutputType(body.expr.clone()));
+            };
+            // Type is impredicative
+            if output == Builtin::Type {
+                Ok(builtin(Builtin::Type))
+            } else {
+                Ok(builtin(max_const(input, output)))
+            }
+        },
+        Expr::Application(vec) => {
+            let mut f_type = type_node(ctx, &vec[0])?;
+            for a in &vec[1..] {
+                let Expr::FnType(x, input, output) = f_type else {
+                    return Err(TypeError::NotAFunction(f_type));
+                };
+                let a_type = type_node(ctx, a)?;
+                if !equivalent(&input, &a_type) {
+                    return Err(locate(ctx, a, TypeError::ArgMismatch { expected: input.expr, found: a_type }));
+                }
+                f_type = ctx.normalize(&instantiate(&x, a, &output));
+            }
+            Ok(f_type)
+        },
+        Expr::LetIn(..) | Expr::Let(..) => {
+            let (name, t, v, rest) = split_let(expr).unwrap();
+            let v_type = type_node(ctx, &v)?;
+            if let Some(t) = t {
+                type_node(ctx, &t)?;
+                let t = ctx.normalize(&t);
+                if !equivalent(&t, &v_type) {
+                    return Err(TypeError::AnnotMismatch { expected: t, found: v_type });
+                }
+            }
+            // the body is checked with `name` in scope, its type does not refer to `name` since
+            // the context substitutes let bound variables wherever a type depends on them
+            let body_type = type_node(&ctx.define(&name, v_type, v.expr), &rest)?;
+            Ok(shift(-1, &name, 0, &body_type))
+        },
+        Expr::Annot(e, t) => {
+            type_node(ctx, t)?;
+            let t = ctx.normalize(t);
+            let e_type = type_node(ctx, e)?;
+            if equivalent(&t, &e_type) {
+                Ok(t)
+            } else {
+                Err(TypeError::AnnotMismatch { expected: t, found: e_type })
+            }
+        },
+        Expr::IfThenElse(c, t, e) => {
+            expect_node(ctx, &bool_t, c)?;
+            let t_type = type_node(ctx, t)?;
+            if universe(ctx, &t_type)? != Some(Builtin::Type) {
+                return Err(TypeError::Expected { expected: "a term in the if branch".to_string(), found: t_type });
+            }
+            let e_type = type_node(ctx, e)?;
+            if equivalent(&t_type, &e_type) {
+                Ok(t_type)
+            } else {
+                Err(TypeError::IfBranchMismatch(t_type, e_type))
+            }
+        },
+
+        // Operators
+        Expr::Plus(l, r) | Expr::Op(Op::Times(l, r)) => {
+            expect_node(ctx, &natural_t, l)?;
+            expect_node(ctx, &natural_t, r)?;
+            Ok(natural_t)
+        },
+        Expr::TextAppend(l, r) => {
+            expect_node(ctx, &text_t, l)?;
+            expect_node(ctx, &text_t, r)?;
+            Ok(text_t)
+        },
+        Expr::And(l, r)
+        | Expr::Or(l, r)
+        | Expr::Equal(l, r)
+        | Expr::NotEqual(l, r) => {
+            expect_node(ctx, &bool_t, l)?;
+            expect_node(ctx, &bool_t, r)?;
+            Ok(bool_t)
+        },
+        Expr::ListAppend(l, r) => {
+            let l_type = type_node(ctx, l)?;
+            if list_element_type(&l_type).is_none() {
+                return Err(TypeError::Expected { expected: "a list".to_string(), found: l_type });
+            }
+            expect_node(ctx, &l_type, r)?;
+            Ok(l_type)
+        },
+        Expr::Op(Op::Equivalent(l, r)) => {
+            let l_type = type_node(ctx, l)?;
+            if universe(ctx, &l_type)? != Some(Builtin::Type) {
+                return Err(TypeError::Expected { expected: "a term on the left side of ===".to_string(), found: l_type });
+            }
+            expect_node(ctx, &l_type, r)?;
+            Ok(builtin(Builtin::Type))
+        },
+        Expr::Assert(t) => {
+            let t_type = type_node(ctx, t)?;
+            if as_const(&t_type) != Some(Builtin::Type) {
+                return Err(TypeError::Expected { expected: "a type".to_string(), found: t_type });
+            }
+            match ctx.normalize(t) {
+                Expr::Op(Op::Equivalent(l, r)) => {
+                    if equivalent(&l, &r) {
+                        Ok(Expr::Op(Op::Equivalent(l, r)))
+                    } else {
+                        let diff = diff(&l, &r);
+                        Err(TypeError::AssertionFailed { left: Box::new(l.expr), right: Box::new(r.expr), diff })
+                    }
+                },
+                t => Err(TypeError::Expected { expected: "an equivalence".to_string(), found: t }),
+            }
+        },
+        Expr::Op(Op::ImportAlt(l, _)) => type_node(ctx, l),
+
+        // Optionals and lists
+        Expr::Some(e) => {
+            let t = type_node(ctx, e)?;
+            if universe(ctx, &t)? != Some(Builtin::Type) {
+                return Err(TypeError::Expected { expected: "a term inside Some".to_string(), found: t });
+            }
+            Ok(app(builtin(Builtin::Optional), vec![t]))
+        },
+        Expr::ListLit(items) => {
+            let Some(first) = items.first() else {
+                return Err(TypeError::InternalBug("Empty list literal without type annotation.".to_string()));
+            };
+            let t = type_node(ctx, first)?;
+            if universe(ctx, &t)? != Some(Builtin::Type) {
+                return Err(TypeError::Expected { expected: "a term inside a list".to_string(), found: t });
+            }
+            for item in &items[1..] {
+                let item_t = type_node(ctx, item)?;
+                if !equivalent(&t, &item_t) {
+                    return Err(locate(ctx, item, TypeError::HeterogenousList { expected: t, found: item_t }));
+                }
+            }
+            Ok(app(builtin(Builtin::List), vec![t]))
+        },
+        Expr::EmptyListLit(t) => {
+            type_node(ctx, t)?;
+            let t = ctx.normalize(t);
+            match list_element_type(&t) {
+                Some(a) if universe(ctx, &a)? == Some(Builtin::Type) => Ok(t),
+                _ => Err(TypeError::InvalidListType(t)),
+            }
+        },
+
+        // Records
+        Expr::RecordLit(items) => record_fields_type(ctx, &desugar_record_lit(items)),
+        Expr::Record(map) => record_fields_type(ctx, map),
+        Expr::RecordType(map) => {
+            let mut c = Builtin::Type;
+            for t in map.values() {
+                let Some(u) = as_const(&type_node(ctx, t)?) else {
+                    return Err(TypeError::InvalidFieldType(t.expr.clone()));
+                };
+                c = max_const(c, u);
+            }
+            Ok(builtin(c))
+        },
+        Expr::Select(e, k) => {
+            match type_node(ctx, e)? {
+                Expr::RecordType(map) => map.get(k).map(|t| t.expr.clone()).ok_or_else(|| TypeError::MissingField(k.clone())),
+                t if as_const(&t).is_some() => {
+                    let union = ctx.normalize(e);
+                    let Expr::UnionType(alternatives) = &union else {
+                        return Err(TypeError::Expected { expected: "a record or union".to_string(), found: t });
+                    };
+                    match alternatives.get(k) {
+                        Some(Some(t)) => Ok(Expr::FnType(k.clone(), Box::new(t.clone()), bx(shift(1, k, 0, &union)))),
+                        Some(None) => Ok(union),
+                        None => Err(TypeError::MissingAlternative(k.clone())),
+                    }
+                },
+                t => Err(TypeError::Expected { expected: "a record or union".to_string(), found: t }),
+            }
+        },
+        Expr::Project(e, labels) => {
+            let mut fields = record_type_of(ctx, e)?;
+            let mut projected = BTreeMap::new();
+            for k in labels {
+                let t = fields.remove(k).ok_or_else(|| if projected.contains_key(k) {
+                    TypeError::DuplicateProjection(k.clone())
+                } else {
+                    TypeError::MissingField(k.clone())
+                })?;
+                projected.insert(k.clone(), t);
+            }
+            Ok(Expr::RecordType(projected))
+        },
+        Expr::ProjectType(e, t) => {
+            let fields = record_type_of(ctx, e)?;
+            type_node(ctx, t)?;
+            let Expr::RecordType(selected) = ctx.normalize(t) else {
+                return Err(locate(ctx, t, TypeError::Expected { expected: "a record type".to_string(), found: ctx.normalize(t) }));
+            };
+            for (k, expected) in &selected {
+                let found = fields.get(k).ok_or_else(|| locate(ctx, t, TypeError::MissingField(k.clone())))?;
+                if !equivalent(&expected.expr, &found.expr) {
+                    return Err(locate(ctx, t, TypeError::ProjectionMismatch {
+                        field: k.clone(), expected: Box::new(expected.expr.clone()), found: Box::new(found.expr.clone()),
+                    }));
+                }
+            }
+            Ok(Expr::RecordType(selected))
+        },
+        Expr::Combine(l, r) => {
+            let l_type = record_type_of(ctx, l)?;
+            let r_type = record_type_of(ctx, r)?;
+            Ok(Expr::RecordType(merge_record_types(&l_type, &r_type)?))
+        },
+        Expr::Completion(t, r) => {
+            let schema = record_type_of(ctx, t)?;
+            if !(schema.contains_key("Type") && schema.contains_key("default")) {
+                let found = Expr::RecordType(schema);
+                return Err(locate(ctx, t, TypeError::Expected { expected: "a schema with fields Type and default".to_string(), found }));
+            }
+            let expected = ctx.normalize(&Expr::Select(t.clone(), "Type".to_string()));
+            let found = type_with(ctx, &completion(t, r))?;
+            if equivalent(&expected, &found) {
+                return Ok(expected);
+            }
+            if let (Expr::RecordType(e), Expr::RecordType(f)) = (&expected, &found) {
+                let missing: Vec<String> = e.keys().filter(|k| !f.contains_key(*k)).cloned().collect();
+                if !missing.is_empty() {
+                    return Err(TypeError::MissingCompletionFields(missing));
+                }
+            }
+            Err(TypeError::AnnotMismatch { expected, found })
+        },
+        Expr::With(e, path, v) => with_type(type_node(ctx, e)?, path, type_node(ctx, v)?).map_err(|err| locate(ctx, e, err)),
+        Expr::Prefer(l, r) => {
+            let mut l_type = record_type_of(ctx, l)?;
+            let r_type = record_type_of(ctx, r)?;
+            l_type.extend(r_type);
+            Ok(Expr::RecordType(l_type))
+        },
+        Expr::Op(Op::CombineTypes(l, r)) => {
+            let mut c = Builtin::Type;
+            let mut fields = Vec::new();
+            for e in [l, r] {
+                let Some(u) = as_const(&type_node(ctx, e)?) else {
+                    return Err(TypeError::Expected { expected: "a record type".to_string(), found: type_node(ctx, e)? });
+                };
+                let Expr::RecordType(map) = ctx.normalize(e) else {
+                    return Err(TypeError::Expected { expected: "a record type".to_string(), found: ctx.normalize(e) });
+                };
+                c = max_const(c, u);
+                fields.push(map);
+            }
+            merge_record_types(&fields[0], &fields[1])?;
+            Ok(builtin(c))
+        },
+
+        Expr::Merge(h, u, annot) => {
+            let handlers = record_type_of(ctx, h)?;
+            let alternatives = match type_node(ctx, u)? {
+                Expr::UnionType(map) => map,
+                t => match t {
+                    Expr::Application(ref vec) if vec.len() == 2 && vec[0].expr == builtin(Builtin::Optional) => {
+                        BTreeMap::from([("None".to_string(), None), ("Some".to_string(), Some(vec[1].clone()))])
+                    },
+                    t => return Err(locate(ctx, u, TypeError::Expected { expected: "a union or an Optional".to_string(), found: t })),
+                },
+            };
+            if let Some(k) = handlers.keys().find(|k| !alternatives.contains_key(*k)) {
+                return Err(TypeError::UnusedHandler(k.clone()));
+            }
+
+            let mut result: Option<Expr> = match annot {
+                Some(t) => {
+                    type_node(ctx, t)?;
+                    Some(ctx.normalize(t))
+                },
+                None => None,
+            };
+            for (k, alternative) in &alternatives {
+                let Some(handler) = handlers.get(k) else {
+                    return Err(TypeError::MissingHandler(k.clone()));
+                };
+                let output = match alternative {
+                    Some(input) => {
+                        let Expr::FnType(x, t, output) = &handler.expr else {
+                            return Err(TypeError::HandlerNotAFunction(k.clone(), handler.expr.clone()));
+                        };
+                        if !equivalent(input, t) {
+                            return Err(TypeError::HandlerMismatch { handler: k.clone(), expected: Box::new(input.expr.clone()), found: Box::new(t.expr.clone()) });
+                        }
+                        if free_in(x, 0, output) {
+                            return Err(TypeError::DependentHandler(k.clone()));
+                        }
+                        shift(-1, x, 0, output)
+                    },
+                    None => handler.expr.clone(),
+                };
+                match &result {
+                    Some(t) if !equivalent(t, &output) => {
+                        return Err(TypeError::HandlerMismatch { handler: k.clone(), expected: Box::new(t.clone()), found: Box::new(output) });
+                    },
+                    Some(_) => {},
+                    None => result = Some(output),
+                }
+            }
+            result.ok_or(TypeError::EmptyMerge)
+        },
+
+        Expr::ShowConstructor(e) => match type_node(ctx, e)? {
+            Expr::UnionType(_) => Ok(builtin(Builtin::Text)),
+            Expr::Application(vec) if vec.len() == 2 && vec[0].expr == builtin(Builtin::Optional) => Ok(builtin(Builtin::Text)),
+            t => Err(locate(ctx, e, TypeError::Expected { expected: "a union or an Optional".to_string(), found: t })),
+        },
+        Expr::ToMap(e, annot) => {
+            let fields = record_type_of(ctx, e)?;
+            let entry_type = |t: &Expr| app(builtin(Builtin::List), vec![Expr::RecordType(BTreeMap::from([
+                ("mapKey".to_string(), builtin(Builtin::Text).into()),
+                ("mapValue".to_string(), t.clone().into()),
+            ]))]);
+            let annot = match annot {
+                Some(t) => {
+                    type_node(ctx, t)?;
+                    Some(ctx.normalize(t))
+                },
+                None => None,
+            };
+            let mut values = fields.values();
+            let Some(first) = values.next() else {
+                // the annotation has to provide the type of the values
+                let t = annot.ok_or(TypeError::EmptyToMap)?;
+                return match &t {
+                    Expr::Application(vec) if vec.len() == 2 && vec[0].expr == builtin(Builtin::List) => match &vec[1].expr {
+                        Expr::RecordType(map) if map.len() == 2 && map.get("mapKey").is_some_and(|k| k.expr == builtin(Builtin::Text))
+                            && map.get("mapValue").is_some_and(|v| type_with(ctx, v).is_ok_and(|k| k == builtin(Builtin::Type))) => Ok(t),
+                        _ => Err(TypeError::InvalidToMapType(t)),
+                    },
+                    _ => Err(TypeError::InvalidToMapType(t)),
+                };
+            };
+            if type_with(ctx, first)? != builtin(Builtin::Type) {
+                return Err(TypeError::InvalidToMapType(first.expr.clone()));
+            }
+            if let Some(other) = values.find(|t| !equivalent(first, t)) {
+                return Err(TypeError::HeterogenousToMap { expected: Box::new(first.expr.clone()), found: Box::new(other.expr.clone()) });
+            }
+            let t = entry_type(first);
+            match annot {
+                Some(a) if !equivalent(&a, &t) => Err(TypeError::AnnotMismatch { expected: a, found: t }),
+                _ => Ok(t),
+            }
+        },
+
+        // Unions
+        Expr::UnionType(map) => {
+            let mut c = Builtin::Type;
+            for t in map.values().flatten() {
+                let Some(u) = as_const(&type_node(ctx, t)?) else {
+                    return Err(TypeError::InvalidAlternativeType(t.expr.clone()));
+                };
+                c = max_const(c, u);
+            }
+            Ok(builtin(c))
+        },
+
+        Expr::Import(..) => Err(TypeError::InternalBug("Encountered unresolved import.".to_string())),
+        Expr::Error => Err(TypeError::InternalBug("Encountered expression that failed to parse.".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("dhall").join("<test>.dhall")
+    }
+
+    fn parse(code: &str) -> Node {
+        let (ast, errs) = parse2::parse(code);
+        assert!(errs.is_empty(), "{code:?} failed to parse: {errs:?}");
+        resolve(&ast.unwrap(), &file()).unwrap()
+    }
+
+    // Type of `code`, or the error without its source locations.
+    fn type_of(code: &str) -> Result<String, TypeError> {
+        fn innermost(e: TypeError) -> TypeError {
+            match e {
+                TypeError::At(_, e) => innermost(*e),
+                e => e,
+            }
+        }
+        typecheck(&parse(code), &file()).map(|t| t.to_string()).map_err(innermost)
+    }
+
+    fn normal(code: &str) -> String {
+        normalize(&strip_spans(&parse(code))).to_string()
+    }
+
+    #[test]
+    fn merge() {
+        let union = "< A : Natural | B >";
+        assert_eq!(type_of(&format!("merge {{ A = \\(n : Natural) -> n, B = 0 }} ({union}.A 1)")).unwrap(), "Natural");
+        assert_eq!(normal(&format!("merge {{ A = \\(n : Natural) -> n, B = 0 }} ({union}.A 1)")), "1");
+        assert_eq!(type_of("merge { Some = \\(b : Bool) -> b, None = False } (Some True)").unwrap(), "Bool");
+        assert_eq!(type_of("\\(u : <>) -> merge {=} u : Text").unwrap(), "∀(u : <>) → Text");
+        assert!(matches!(type_of("\\(u : < >) -> merge {=} u"), Err(TypeError::EmptyMerge)));
+        assert!(matches!(type_of(&format!("merge {{ A = \\(n : Natural) -> n }} ({union}.B)")),
+            Err(TypeError::MissingHandler(k)) if k == "B"));
+        assert!(matches!(type_of(&format!("merge {{ A = \\(n : Natural) -> n, B = 0, C = 1 }} ({union}.B)")),
+            Err(TypeError::UnusedHandler(k)) if k == "C"));
+        assert!(matches!(type_of(&format!("merge {{ A = \\(n : Natural) -> n, B = True }} ({union}.B)")),
+            Err(TypeError::HandlerMismatch { handler, .. }) if handler == "B"));
+    }
+
+    #[test]
+    fn with() {
+        assert_eq!(type_of("{ a = { b = 1 } } with a.c = True").unwrap(), "{ a : { b : Natural, c : Bool } }");
+        assert_eq!(normal("{ a = { b = 1 } } with a.b = 2"), "{ a = { b = 2 } }");
+        assert_eq!(normal("(Some 1) with ? = 2"), "Some 2");
+        assert_eq!(normal("(None Natural) with ? = 2"), "None Natural");
+        assert_eq!(normal("\\(r : { a : Natural }) -> r with a = 1"), "λ(r : { a : Natural }) → r with a = 1");
+        assert!(matches!(type_of("(Some 1) with ? = True"), Err(TypeError::WithOptionalMismatch { .. })));
+        assert!(type_of("1 with a = 2").is_err());
+    }
+
+    #[test]
+    fn completion() {
+        let schema = "{ Type = { name : Text, port : Natural }, default = { port = 80 } }";
+        assert_eq!(type_of(&format!("{schema}::{{ name = \"a\" }}")).unwrap(), "{ name : Text, port : Natural }");
+        assert_eq!(normal(&format!("{schema}::{{ name = \"a\" }}")), "{ name = \"a\", port = 80 }");
+        assert_eq!(normal(&format!("{schema}::{{ name = \"a\", port = 8080 }}")), "{ name = \"a\", port = 8080 }");
+        assert!(matches!(type_of(&format!("{schema}::{{ port = 1 }}")),
+            Err(TypeError::MissingCompletionFields(fields)) if fields == ["name"]));
+        assert!(matches!(type_of(&format!("let Schema = {schema} in Schema::{{=}}")),
+            Err(TypeError::MissingCompletionFields(fields)) if fields == ["name"]));
+        assert!(type_of(&format!("{schema}::{{ name = 1 }}")).is_err());
+    }
+
+    #[test]
+    fn assert_diffs() {
+        assert_eq!(type_of("assert : 1 + 1 === 2").unwrap(), "2 ≡ 2");
+        assert!(type_of("assert : (\\(x : Bool) -> x) === (\\(y : Bool) -> y)").is_ok());
+        let Err(TypeError::AssertionFailed { diff, .. }) = type_of("assert : { a = 1, b = [ 1, 2 ], c = Some True } === { a = 1, b = [ 1, 3 ], c = None Bool }") else {
+            panic!("assertion should fail")
+        };
+        assert_eq!(diff, [".b[1] is 2 on the left but 3 on the right", ".c is Some True on the left but None Bool on the right"]);
+        let Err(TypeError::AssertionFailed { diff, .. }) = type_of("assert : [ { a = Some 1 } ] === [ { a = Some 2 } ]") else {
+            panic!("assertion should fail")
+        };
+        assert_eq!(diff, ["[0].a.? is 1 on the left but 2 on the right"]);
+        let Err(TypeError::AssertionFailed { diff, .. }) = type_of("assert : 1 === 2") else { panic!("assertion should fail") };
+        assert!(diff.is_empty());
+        assert!(matches!(type_of("assert : 1"), Err(TypeError::Expected { found: Expr::Builtin(Builtin::Natural), .. })));
+        assert!(matches!(type_of("assert : Natural"), Err(TypeError::Expected { expected, .. }) if expected == "an equivalence"));
+    }
+
+    #[test]
+    fn import_alternatives() {
+        import2::set_environment(BTreeMap::from([
+            ("GOOD".to_string(), "\"good\"".to_string()),
+            ("ILL_TYPED".to_string(), "1 + True".to_string()),
+            ("UNPARSABLE".to_string(), "{ a = ".to_string()),
+        ]));
+        // any error while resolving or checking the import selects the alternative
+        assert_eq!(crate::eval("env:MISSING ? \"fallback\"").unwrap(), "\"fallback\"");
+        assert_eq!(crate::eval("env:ILL_TYPED ? \"fallback\"").unwrap(), "\"fallback\"");
+        assert_eq!(crate::eval("env:UNPARSABLE ? \"fallback\"").unwrap(), "\"fallback\"");
+        assert_eq!(crate::eval("env:MISSING ? env:ILL_TYPED ? env:GOOD").unwrap(), "\"good\"");
+        assert_eq!(crate::eval("env:GOOD ? env:MISSING").unwrap(), "\"good\"");
+        assert!(crate::eval("env:MISSING ? env:ILL_TYPED").is_err());
+        // the checker and the compiler see the same alternative
+        let code = "(env:ILL_TYPED ? \"fallback\") ++ \"!\"";
+        assert_eq!(type_of(code).unwrap(), "Text");
+        assert_eq!(crate::eval(code).unwrap(), "\"fallback!\"");
+    }
+
+    #[test]
+    fn fold() {
+        // type checking does not evaluate folds, not even those bound by let
+        assert_eq!(type_of("Natural/fold 1000000000 Natural (\\(x : Natural) -> x * 2) 1").unwrap(), "Natural");
+        assert_eq!(type_of("let n = Natural/fold 1000000000 Natural (\\(x : Natural) -> x * 2) 1 in [ n ]").unwrap(), "List Natural");
+        // asserts normalize them, successors without applying them n times
+        assert!(type_of("assert : Natural/fold 1000000000 Natural (\\(x : Natural) -> x + 2) 1 === 2000000001").is_ok());
+        assert!(type_of("let one = 1 in assert : Natural/fold 1000000000 Natural (\\(x : Natural) -> one + x) 0 === 1000000000").is_ok());
+        assert_eq!(normal("Natural/fold 3 Natural (\\(x : Natural) -> x * 2) 1"), "8");
+        assert_eq!(normal("\\(n : Natural) -> Natural/fold 2 Natural (\\(x : Natural) -> x + n) 0"), "λ(n : Natural) → n + n");
+    }
+
+    #[test]
+    fn integer_to_double() {
+        let huge = format!("1{}", "0".repeat(400));
+        assert_eq!(normal(&format!("Integer/toDouble -{huge}")), "-Infinity");
+        assert_eq!(normal(&format!("Integer/toDouble +{huge}")), "Infinity");
+        assert_eq!(normal("Integer/toDouble +12"), "12.0");
+    }
+
+    // the type, the normal form and the value computed by the VM describe the same updated expression
+    #[test]
+    fn with_agrees_with_evaluation() {
+        for (code, t, normal_form, value) in [
+            ("{=} with a.b = 1", "{ a : { b : Natural } }", "{ a = { b = 1 } }", "{ a = { b = 1 } }"),
+            ("None Natural with ? = 1", "Optional Natural", "None Natural", "None"),
+            ("Some 1 with ? = 2", "Optional Natural", "Some 2", "Some 2"),
+            ("{ a = Some { b = 1 } } with a.?.b = 2", "{ a : Optional { b : Natural } }", "{ a = Some { b = 2 } }", "{ a = Some { b = 2 } }"),
+            ("{ a = { b = 1, c = True } } with a.b = \"x\"", "{ a : { b : Text, c : Bool } }", "{ a = { b = \"x\", c = True } }", "{ a = { b = \"x\", c = True } }"),
+        ] {
+            assert_eq!(type_of(code).unwrap(), t, "type of {code}");
+            assert_eq!(normal(code), normal_form, "normal form of {code}");
+            assert_eq!(type_of(normal_form).unwrap(), t, "type of the normal form of {code}");
+            assert_eq!(crate::eval(code).unwrap(), value, "value of {code}");
+        }
+        assert!(matches!(type_of("None Natural with ? = True"), Err(TypeError::WithOptionalMismatch { .. })));
+        assert!(matches!(type_of("{ a = 1 } with a.b = 2"), Err(TypeError::Expected { .. })));
+        assert!(matches!(type_of("{ a = None Natural } with a.?.b = 2"), Err(TypeError::Expected { .. })));
+    }
+}
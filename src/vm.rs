@@ -1,41 +1,14 @@
-
-use std::borrow::BorrowMut;
+use std::cmp::Ordering;
 use std::cell::RefCell;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::BTreeMap;
 use std::rc::Rc;
 
-use crate::bytecode::{Op, Value, Function, Closure, UpvalueLoc, Upvalue, UpvalI, Builtin};
-use crate::error::RuntimeError;
-
-thread_local! {
-    static IMPORT_VALS: RefCell<Vec<Value>> = RefCell::new(Vec::new());
-    static IMPORT_INDICES: RefCell<HashMap<String, usize>> = RefCell::new(HashMap::new());
-}
-
-pub fn add_import_value(name: String, val: Value) -> usize {
-    let idx = IMPORT_VALS.with(|imports| {
-        imports.borrow_mut().push(val);
-        imports.borrow().len() - 1
-    });
-    IMPORT_INDICES.with(|map| {
-        map.borrow_mut().insert(name, idx);
-    });
-    idx
-}
-
-pub fn get_import_index(name: &str) -> Option<usize> {
-    IMPORT_INDICES.with(|map| {
-        map.borrow().get(name).cloned()
-    })
-}
+use crate::bytecode::{Op, Value, Function, Closure, UpvalueLoc, Upvalue, UpvalI, Builtin, builtin_fn_args};
+use num_bigint::BigUint;
+use num_traits::{One, Zero};
 
-fn get_import_value(import_idx: usize) -> Result<Value, RuntimeError> {
-    IMPORT_VALS.with(|imports| {
-        imports.borrow()
-            .get(import_idx).cloned()
-            .ok_or_else(|| RuntimeError::Basic("Failed to import function".to_string()))
-    })
-}
+use crate::ast::{Expr, PathComponent, escape_text};
+use crate::error::RuntimeError;
 
 #[derive(Default)]
 pub struct Vm {
@@ -59,6 +32,10 @@ impl CallFrame {
         r
     }
 
+    fn jump(&mut self, offset: usize) {
+        self.ip += offset;
+    }
+
     fn peek(&self) -> Op {
         self.closure.func.chunk.code[self.ip].clone()
     }
@@ -86,15 +63,32 @@ impl Vm {
         let closure = Closure::new(function);
         self.stack.push(Value::Closure(closure));  // this is pretty bad.. we shouldn't need to keep two function copies around.
         self.call(0)?;
-        while !self.done() {
-            self.step()?;
-        }
+        self.run_until(0)?;
         // result is on top of stack
         self.pop_stack()
     }
 
-    fn done(&self) -> bool {
-        self.frames.len() == 0
+    // Execute ops until the call stack shrinks back to `depth` frames.
+    fn run_until(&mut self, depth: usize) -> Result<(), RuntimeError> {
+        while self.frames.len() > depth {
+            self.step().map_err(|e| self.locate(e))?;
+        }
+        Ok(())
+    }
+
+    // Apply `func` to `args` and run it to completion, so builtins can call back into functions.
+    fn apply(&mut self, func: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        let mut func = func;
+        for arg in args {
+            // one argument at a time, builtins collect them until they have all of them
+            self.push_stack(func);
+            self.push_stack(arg);
+            let depth = self.frames.len();
+            self.call_value(1)?;
+            self.run_until(depth)?;
+            func = self.pop_stack()?;
+        }
+        Ok(func)
     }
 
     fn push_frame(&mut self, closure: Closure, stack_offset: usize) {
@@ -122,6 +116,26 @@ impl Vm {
         Ok(())
     }
 
+    // Apply the value below the top `nargs` stack values to them.
+    fn call_value(&mut self, nargs: usize) -> Result<(), RuntimeError> {
+        match self.peek_stack(nargs)?.clone() {
+            Value::Builtin(b) => self.call_builtin(b, Vec::new(), nargs),
+            Value::PartialBuiltin(b, args) => self.call_builtin(b, args, nargs),
+            Value::Type => {
+                // applying a type constructor yields another type
+                self.stack.truncate(self.stack.len() - nargs);
+                Ok(())
+            },
+            Value::Constructor(label) => {
+                let val = self.pop_stack()?;
+                let _ = self.pop_stack()?;  // remove Value::Constructor
+                self.push_stack(Value::Union(label, Some(Box::new(val))));
+                Ok(())
+            },
+            _ => self.call(nargs),
+        }
+    }
+
     fn step(&mut self) -> Result<(), RuntimeError> {
         let op = self.frame_mut().advance();
 
@@ -137,7 +151,18 @@ impl Vm {
                 match (l, r) {
                     (Value::Natural(l), Value::Natural(r)) =>
                         self.push_stack(Value::Natural(l + r)),
-                    _ => todo!()
+                    (Value::Successor(l), Value::Natural(r)) | (Value::Natural(l), Value::Successor(r)) =>
+                        self.push_stack(Value::Successor(l + r)),
+                    (l, r) => Err(RuntimeError::Basic(format!("Cannot add {l:?} and {r:?}, + is only defined for Naturals.")))?
+                }
+            },
+            Op::Mul => {
+                let r = self.pop_stack()?;
+                let l = self.pop_stack()?;
+                match (l, r) {
+                    (Value::Natural(l), Value::Natural(r)) =>
+                        self.push_stack(Value::Natural(l * r)),
+                    (l, r) => Err(RuntimeError::Basic(format!("Cannot multiply {l:?} and {r:?}, * is only defined for Naturals.")))?
                 }
             },
             Op::TextAppend => {
@@ -147,7 +172,7 @@ impl Vm {
                     self.push_stack(Value::String(ls + &rs));
                 } else {
                     Err(RuntimeError::Basic(
-                        format!("Concatenation is only allowed for Strings.")
+                        "Concatenation is only allowed for Strings.".to_string()
                     ))?
                 }
             },
@@ -159,7 +184,7 @@ impl Vm {
                         li.append(&mut ri);
                         self.push_stack(Value::List(li));
                     },
-                    _ => Err(RuntimeError::Basic(format!("ListAppend expects two lists.")))?
+                    _ => Err(RuntimeError::Basic("ListAppend expects two lists.".to_string()))?
                 }
             },
             Op::Equal => {
@@ -169,7 +194,7 @@ impl Vm {
                     (Value::Bool(li), Value::Bool(ri)) => {
                         self.push_stack(Value::Bool(li == ri));
                     },
-                    _ => Err(RuntimeError::Basic(format!("Equal can only be used on bools.")))?
+                    _ => Err(RuntimeError::Basic("Equal can only be used on bools.".to_string()))?
                 }
             },
             Op::NotEqual => {
@@ -179,27 +204,15 @@ impl Vm {
                     (Value::Bool(li), Value::Bool(ri)) => {
                         self.push_stack(Value::Bool(li != ri));
                     },
-                    _ => Err(RuntimeError::Basic(format!("NotEqual can only be used on bools.")))?
+                    _ => Err(RuntimeError::Basic("NotEqual can only be used on bools.".to_string()))?
                 }
             },
-            Op::And => {
-                let r = self.pop_stack()?;
-                let l = self.pop_stack()?;
-                match (l, r) {
-                    (Value::Bool(li), Value::Bool(ri)) => {
-                        self.push_stack(Value::Bool(li && ri));
-                    },
-                    _ => Err(RuntimeError::Basic(format!("And can only be used on bools.")))?
-                }
-            },
-            Op::Or => {
-                let r = self.pop_stack()?;
-                let l = self.pop_stack()?;
-                match (l, r) {
-                    (Value::Bool(li), Value::Bool(ri)) => {
-                        self.push_stack(Value::Bool(li || ri));
-                    },
-                    _ => Err(RuntimeError::Basic(format!("Or can only be used on bools.")))?
+            Op::Jump(offset) => self.frame_mut().jump(offset),
+            Op::JumpIfFalse(offset) => {
+                match self.peek_stack(0)? {
+                    Value::Bool(false) => self.frame_mut().jump(offset),
+                    Value::Bool(true) => (),
+                    val => Err(RuntimeError::Basic(format!("Condition must be a Bool, got {val:?} instead.")))?,
                 }
             },
             Op::Combine => {
@@ -218,8 +231,32 @@ impl Vm {
                         }
                         self.push_stack(l);
                     },
-                    _ => Err(RuntimeError::Basic(format!("Prefer expression can only be used on records.")))?
+                    _ => Err(RuntimeError::Basic("Prefer expression can only be used on records.".to_string()))?
+                }
+            },
+            Op::Complete => {
+                let r = self.pop_stack()?.assume_record()?;
+                let mut schema = self.pop_stack()?.assume_record()?;
+                let Some(Value::Record(mut completed)) = schema.remove("default") else {
+                    Err(RuntimeError::InternalBug("Record completion requires a default record.".to_string()))?
+                };
+                completed.extend(r);
+                // the typechecker rejects records that lack fields without a default or have extra ones
+                if let Some(Value::RecordType(fields)) = schema.get("Type") {
+                    if !completed.keys().eq(fields.iter()) {
+                        Err(RuntimeError::InternalBug(format!("Completed record has the fields {:?} instead of {fields:?}.",
+                            completed.keys().collect::<Vec<_>>())))?
+                    }
                 }
+                self.push_stack(Value::Record(completed));
+            },
+            Op::With(const_idx) => {
+                let Value::Path(path) = self.func().chunk.get_constant(const_idx)? else {
+                    Err(RuntimeError::InternalBug("With expression requires a path.".to_string()))?
+                };
+                let new = self.pop_stack()?;
+                let val = self.pop_stack()?;
+                self.push_stack(update(val, &path, new)?);
             },
             Op::CreateRecord(n) => {
                 let mut map = BTreeMap::new();
@@ -237,14 +274,43 @@ impl Vm {
                 for _ in 0..n {
                     list.push(self.pop_stack()?);
                 }
+                list.reverse();
                 self.push_stack(Value::List(list));
             },
+            Op::Select(const_idx) => {
+                let label = self.func().chunk.get_constant(const_idx)?.assume_string()?;
+                match self.pop_stack()? {
+                    Value::Record(mut map) => match map.remove(&label) {
+                        Some(val) => self.push_stack(val),
+                        None => Err(RuntimeError::Basic(format!("Record has no field {label}.")))?,
+                    },
+                    Value::UnionType(alternatives) => match alternatives.get(&label) {
+                        Some(true) => self.push_stack(Value::Constructor(label)),
+                        Some(false) => self.push_stack(Value::Union(label, None)),
+                        None => Err(RuntimeError::Basic(format!("Union has no alternative {label}.")))?,
+                    },
+                    val => Err(RuntimeError::Basic(format!("Cannot select {label} from {val:?}.")))?,
+                }
+            },
+            Op::Project => {
+                let Value::RecordType(labels) = self.pop_stack()? else {
+                    Err(RuntimeError::InternalBug("Projection requires a record type.".to_string()))?
+                };
+                let mut map = self.pop_stack()?.assume_record()?;
+                let mut projected = BTreeMap::new();
+                for label in labels {
+                    let val = map.remove(&label)
+                        .ok_or_else(|| RuntimeError::Basic(format!("Record has no field {label}.")))?;
+                    projected.insert(label, val);
+                }
+                self.push_stack(Value::Record(projected));
+            },
             Op::Constant(const_idx) => self.stack.push(self.func().chunk.get_constant(const_idx)?),
             Op::Closure(const_idx) => {
                 let func = self.func().chunk.get_constant(const_idx)?;
                 let mut closure = if let Value::Function(func) = func {
                     Closure::new(func)
-                } else { Err(RuntimeError::InternalBug(format!("Closure requires a function.")))? };
+                } else { Err(RuntimeError::InternalBug("Closure requires a function.".to_string()))? };
 
                 // let frame = self.frame_mut();
                 while let Op::Upval(upval) = self.frame()?.peek() {
@@ -263,20 +329,52 @@ impl Vm {
                             self.frame()?.closure.upvalues[idx].clone()
                         },
                     };
-                    println!("Pushing upvalue {upval:?} created closure");
+                    if self.debug {
+                        println!("Pushing upvalue {upval:?} created closure");
+                    }
                     closure.upvalues.push(upval.clone());
                     self.upvalues.push(upval);
                     self.frame_mut().advance();
                 }
                 self.push_stack(Value::Closure(closure));
             },
-            Op::Call(nargs) => {
-                if let Value::Builtin(b) = self.peek_stack(nargs)?.clone() {
-                    self.apply_builtin_fn(&b)?;
-                } else {
-                    self.call(nargs)?;
+            Op::Call(nargs) => self.call_value(nargs)?,
+            Op::Merge => {
+                let union = self.pop_stack()?;
+                let mut handlers = self.pop_stack()?.assume_record()?;
+                let (label, val) = match union {
+                    Value::Union(label, val) => (label, val),
+                    Value::Option(Some(val)) => ("Some".to_string(), Some(val)),
+                    Value::Option(None) => ("None".to_string(), None),
+                    val => Err(RuntimeError::Basic(format!("Cannot merge {val:?}, expected a union or an Optional.")))?,
+                };
+                let handler = handlers.remove(&label)
+                    .ok_or_else(|| RuntimeError::Basic(format!("No handler for alternative {label}.")))?;
+                self.push_stack(handler);
+                if let Some(val) = val {
+                    self.push_stack(*val);
+                    self.call_value(1)?;
                 }
             },
+            Op::ShowConstructor => {
+                let label = match self.pop_stack()? {
+                    Value::Union(label, _) => label,
+                    Value::Option(Some(_)) => "Some".to_string(),
+                    Value::Option(None) => "None".to_string(),
+                    val => Err(RuntimeError::Basic(format!("Cannot show the constructor of {val:?}, expected a union or an Optional.")))?,
+                };
+                self.push_stack(Value::String(label));
+            },
+            Op::ToMap => {
+                let record = self.pop_stack()?.assume_record()?;
+                let entries = record.into_iter()
+                    .map(|(k, v)| Value::Record(BTreeMap::from([
+                        ("mapKey".to_string(), Value::String(k)),
+                        ("mapValue".to_string(), v),
+                    ])))
+                    .collect();
+                self.push_stack(Value::List(entries));
+            },
             Op::CloseUpvalue(idx) => {
                 // println!("Lifting upvalue {idx}");
                 let stack_idx = self.frame()?.stack_offset + idx;
@@ -313,13 +411,10 @@ impl Vm {
                 };
                 self.push_stack(val);
             },
-            Op::Import(import_idx) => {
-                self.push_stack(get_import_value(import_idx)?);
-            },
             Op::Builtin(b) => {
                 self.push_stack(Value::Builtin(b));
             },
-            _ => todo!("{:?}", op)
+            Op::Upval(_) => Err(RuntimeError::InternalBug("Upvalue outside of a closure definition.".to_string()))?,
         }
         if self.debug {
             self.print_stack();
@@ -327,6 +422,27 @@ impl Vm {
         Ok(())
     }
 
+    // Apply builtin `b`, which already received `args`, to the top `nargs` stack values. It only
+    // runs once all its arguments are there, until then it is kept as a partially applied builtin.
+    fn call_builtin(&mut self, b: Builtin, mut args: Vec<Value>, nargs: usize) -> Result<(), RuntimeError> {
+        let arity = builtin_fn_args(&b).map_err(|e| RuntimeError::InternalBug(e.to_string()))?;
+        if args.is_empty() && nargs == arity {
+            return self.apply_builtin_fn(&b);
+        }
+        args.extend(self.stack.split_off(self.stack.len() - nargs));
+        let _ = self.pop_stack()?;  // remove the builtin
+        match args.len().cmp(&arity) {
+            Ordering::Less => self.push_stack(Value::PartialBuiltin(b, args)),
+            Ordering::Equal => {
+                self.push_stack(Value::Builtin(b.clone()));
+                self.stack.extend(args);
+                self.apply_builtin_fn(&b)?;
+            },
+            Ordering::Greater => Err(RuntimeError::InternalBug(format!("{b} applied to {} arguments, it takes {arity}.", args.len())))?,
+        }
+        Ok(())
+    }
+
     fn apply_builtin_fn(&mut self, b: &Builtin) -> Result<(), RuntimeError> {
         match b {
 
@@ -338,28 +454,28 @@ impl Vm {
             Builtin::NaturalOdd => {
                 let val = self.pop_stack()?.assume_natural()?;
                 let _ = self.pop_stack()?;  // remove Value::Builtin
-                self.push_stack(Value::Bool(val % 2 == 1));
+                self.push_stack(Value::Bool(val.bit(0)));
             },
             Builtin::NaturalEven => {
                 let val = self.pop_stack()?.assume_natural()?;
                 let _ = self.pop_stack()?;  // remove Value::Builtin
-                self.push_stack(Value::Bool(val % 2 == 0));
+                self.push_stack(Value::Bool(!val.bit(0)));
             },
             Builtin::NaturalIsZero => {
                 let val = self.pop_stack()?.assume_natural()?;
                 let _ = self.pop_stack()?;  // remove Value::Builtin
-                self.push_stack(Value::Bool(val == 0));
+                self.push_stack(Value::Bool(val.is_zero()));
             },
             Builtin::NaturalToInteger => {
                 let val = self.pop_stack()?.assume_natural()?;
                 let _ = self.pop_stack()?;  // remove Value::Builtin
-                self.push_stack(Value::Integer(val as i64));
+                self.push_stack(Value::Integer(val.into()));
             },
             Builtin::NaturalSubtract => {
                 let from = self.pop_stack()?.assume_natural()?;
                 let this = self.pop_stack()?.assume_natural()?;
                 let _ = self.pop_stack()?;  // remove Value::Builtin
-                let r = if this >= from { 0 } else { from - this };
+                let r = if from > this { from - this } else { BigUint::zero() };
                 self.push_stack(Value::Natural(r));
             },
             Builtin::NaturalShow => {
@@ -367,8 +483,43 @@ impl Vm {
                 let _ = self.pop_stack()?;  // remove Value::Builtin
                 self.push_stack(Value::String(format!("{val}")));
             },
-            // Builtin::NaturalFold => ,
-            // Builtin::NaturalBuild => ,
+            Builtin::None => {
+                let _ = self.pop_stack()?;  // remove type argument
+                let _ = self.pop_stack()?;  // remove Value::Builtin
+                self.push_stack(Value::Option(None));
+            },
+            Builtin::NaturalFold => {
+                let zero = self.pop_stack()?;
+                let succ = self.pop_stack()?;
+                let _ = self.pop_stack()?;  // remove type argument
+                let n = self.pop_stack()?.assume_natural()?;
+                let _ = self.pop_stack()?;  // remove Value::Builtin
+                let r = match (self.increment(&succ), zero) {
+                    // folding `\(x : Natural) -> x + k` does not need to call it n times
+                    (Some(k), Value::Natural(zero)) => Value::Natural(n * k + zero),
+                    (_, zero) => {
+                        let mut acc = zero;
+                        let mut i = BigUint::zero();
+                        while i < n {
+                            let next = self.apply(succ.clone(), vec![acc.clone()])?;
+                            if next == acc {
+                                // succ has reached a fixed point, the remaining iterations change nothing
+                                break;
+                            }
+                            acc = next;
+                            i += 1u32;
+                        }
+                        acc
+                    },
+                };
+                self.push_stack(r);
+            },
+            Builtin::NaturalBuild => {
+                let g = self.pop_stack()?;
+                let _ = self.pop_stack()?;  // remove Value::Builtin
+                let r = self.apply(g, vec![Value::Type, natural_succ(), Value::Natural(BigUint::zero())])?;
+                self.push_stack(r);
+            },
             Builtin::IntegerNegate => {
                 let val = self.pop_stack()?.assume_integer()?;
                 let _ = self.pop_stack()?;  // remove Value::Builtin
@@ -377,36 +528,109 @@ impl Vm {
             Builtin::IntegerClamp => {
                 let val = self.pop_stack()?.assume_integer()?;
                 let _ = self.pop_stack()?;  // remove Value::Builtin
-                let r = if val < 0 { 0 } else { val as u64 };
-                self.push_stack(Value::Natural(r));
+                self.push_stack(Value::Natural(val.to_biguint().unwrap_or_default()));
             },
             Builtin::IntegerShow => {
                 let val = self.pop_stack()?.assume_integer()?;
                 let _ = self.pop_stack()?;  // remove Value::Builtin
+                self.push_stack(Value::String(Expr::IntegerLit(val).to_string()));
+            },
+            Builtin::IntegerToDouble => {
+                let val = self.pop_stack()?.assume_integer()?;
+                let _ = self.pop_stack()?;  // remove Value::Builtin
+                self.push_stack(Value::Double((&val).into()));
+            },
+            Builtin::DoubleShow => {
+                let val = self.pop_stack()?.assume_double()?;
+                let _ = self.pop_stack()?;  // remove Value::Builtin
                 self.push_stack(Value::String(format!("{val}")));
             },
-            // Builtin::IntegerToDouble => ,
             Builtin::TextReplace => {
                 let text = self.pop_stack()?.assume_string()?;
                 let replacement = self.pop_stack()?.assume_string()?;
                 let pattern = self.pop_stack()?.assume_string()?;
                 let _ = self.pop_stack()?;  // remove Value::Builtin
-                self.push_stack(Value::String(text.replace(&pattern, &replacement)));
+                // an empty needle would match between every character, the spec leaves the text unchanged
+                let r = if pattern.is_empty() { text } else { text.replace(&pattern, &replacement) };
+                self.push_stack(Value::String(r));
+            },
+            Builtin::TextShow => {
+                let val = self.pop_stack()?.assume_string()?;
+                let _ = self.pop_stack()?;  // remove Value::Builtin
+                self.push_stack(Value::String(format!("\"{}\"", escape_text(&val))));
+            },
+            Builtin::DateShow => {
+                let r = match self.pop_stack()? {
+                    Value::Date(y, m, d) => Expr::DateLit(y, m, d).to_string(),
+                    val => Err(RuntimeError::Basic(format!("Expected Date, got {val:?} instead.")))?,
+                };
+                let _ = self.pop_stack()?;  // remove Value::Builtin
+                self.push_stack(Value::String(r));
+            },
+            Builtin::TimeShow => {
+                let r = match self.pop_stack()? {
+                    Value::Time(h, m, s, frac) => Expr::TimeLit(h, m, s, frac).to_string(),
+                    val => Err(RuntimeError::Basic(format!("Expected Time, got {val:?} instead.")))?,
+                };
+                let _ = self.pop_stack()?;  // remove Value::Builtin
+                self.push_stack(Value::String(r));
+            },
+            Builtin::TimeZoneShow => {
+                let r = match self.pop_stack()? {
+                    Value::TimeZone(offset) => Expr::TimeZoneLit(offset).to_string(),
+                    val => Err(RuntimeError::Basic(format!("Expected TimeZone, got {val:?} instead.")))?,
+                };
+                let _ = self.pop_stack()?;  // remove Value::Builtin
+                self.push_stack(Value::String(r));
+            },
+            Builtin::ListIndexed => {
+                let val = self.pop_stack()?.assume_list()?;
+                let _ = self.pop_stack()?;  // remove type argument
+                let _ = self.pop_stack()?;  // remove Value::Builtin
+                let r = val.into_iter().enumerate().map(|(idx, val)| {
+                    let mut map = BTreeMap::new();
+                    map.insert("index".to_string(), Value::Natural(idx.into()));
+                    map.insert("value".to_string(), val);
+                    Value::Record(map)
+                }).collect();
+                self.push_stack(Value::List(r));
+            },
+            Builtin::ListBuild => {
+                let g = self.pop_stack()?;
+                let _ = self.pop_stack()?;  // remove type argument
+                let _ = self.pop_stack()?;  // remove Value::Builtin
+                let r = self.apply(g, vec![Value::Type, list_cons(), Value::List(Vec::new())])?;
+                self.push_stack(r);
+            },
+            Builtin::ListFold => {
+                let nil = self.pop_stack()?;
+                let cons = self.pop_stack()?;
+                let _ = self.pop_stack()?;  // remove type argument
+                let list = self.pop_stack()?.assume_list()?;
+                let _ = self.pop_stack()?;  // remove type argument
+                let _ = self.pop_stack()?;  // remove Value::Builtin
+                let mut acc = nil;
+                for x in list.into_iter().rev() {
+                    acc = self.apply(cons.clone(), vec![x, acc])?;
+                }
+                self.push_stack(acc);
             },
-            // Builtin::TextShow => ,
             Builtin::ListLength => {
                 let val = self.pop_stack()?.assume_list()?;
+                let _ = self.pop_stack()?;  // remove type argument
                 let _ = self.pop_stack()?;  // remove Value::Builtin
-                self.push_stack(Value::Natural(val.len() as u64));
+                self.push_stack(Value::Natural(val.len().into()));
             },
             Builtin::ListReverse => {
                 let mut val = self.pop_stack()?.assume_list()?;
+                let _ = self.pop_stack()?;  // remove type argument
                 let _ = self.pop_stack()?;  // remove Value::Builtin
                 val.reverse();
                 self.push_stack(Value::List(val));
             },
             Builtin::ListHead => {
                 let val = self.pop_stack()?.assume_list()?;
+                let _ = self.pop_stack()?;  // remove type argument
                 let _ = self.pop_stack()?;  // remove Value::Builtin
                 let r = if !val.is_empty() {
                     Some(Box::new(val[0].clone()))
@@ -415,13 +639,14 @@ impl Vm {
             },
             Builtin::ListLast => {
                 let mut val = self.pop_stack()?.assume_list()?;
+                let _ = self.pop_stack()?;  // remove type argument
                 let _ = self.pop_stack()?;  // remove Value::Builtin
                 let r = if !val.is_empty() {
                     Some(Box::new(val.pop().unwrap()))
                 } else { None };
                 self.push_stack(Value::Option(r));
             },
-            _ => todo!("{b:?}")
+            _ => Err(RuntimeError::FunctionCall(Value::Builtin(b.clone())))?,
         }
         Ok(())
     }
@@ -447,20 +672,25 @@ impl Vm {
             }
             // println!("{:04}    {:?}", idx, val);
         }
-        println!("");
+        println!();
     }
 
-    fn frame(&self) -> Result<&CallFrame, RuntimeError> {
-        self.frames.last().ok_or_else(|| RuntimeError::InternalBug(format!("Call stack is empty")))
-    }
-    fn enclosing(&self) -> Result<&CallFrame, RuntimeError> {
-        let len = self.frames.len();
-        if len >= 2 {
-            Ok(&self.frames[len-2])
-        } else {
-            Err(RuntimeError::InternalBug(format!("Outermost frame does not have enclosing frame.")))
+    // Attach the source location of the op that was just executed to `err`.
+    fn locate(&self, err: RuntimeError) -> RuntimeError {
+        if let RuntimeError::At(..) = err {
+            return err;
+        }
+        let location = self.frames.last()
+            .and_then(|frame| frame.closure.func.chunk.location(frame.ip.saturating_sub(1)));
+        match location {
+            Some(location) => RuntimeError::At(location, Box::new(err)),
+            None => err,
         }
     }
+
+    fn frame(&self) -> Result<&CallFrame, RuntimeError> {
+        self.frames.last().ok_or_else(|| RuntimeError::InternalBug("Call stack is empty".to_string()))
+    }
     fn frame_mut(&mut self) -> &mut CallFrame {
         self.frames.last_mut().unwrap()
     }
@@ -470,18 +700,35 @@ impl Vm {
     }
 
     fn pop_stack(&mut self) -> Result<Value, RuntimeError> {
-        self.stack.pop().ok_or_else(|| RuntimeError::StackUnderflow)
+        self.stack.pop().ok_or(RuntimeError::StackUnderflow)
     }
     fn push_stack(&mut self, val: Value) {
         self.stack.push(val);
     }
     fn peek_stack(&self, n: usize) -> Result<&Value, RuntimeError> {
-        if self.stack.len() >= n+1 {
-            Ok(&self.stack.get(self.stack.len()-n-1).unwrap())
+        if self.stack.len() > n {
+            Ok(self.stack.get(self.stack.len()-n-1).unwrap())
         } else {
             Err(RuntimeError::StackUnderflow)
         }
     }
+    // Recognizes functions that add a constant k to a Natural, returning k. The function is applied
+    // once to an unknown Natural, which fails (or returns something else) unless it only adds to it.
+    fn increment(&mut self, func: &Value) -> Option<BigUint> {
+        let (stack_len, frames_len) = (self.stack.len(), self.frames.len());
+        match self.apply(func.clone(), vec![Value::Successor(BigUint::zero())]) {
+            Ok(Value::Successor(k)) => Some(k),
+            Ok(_) => None,
+            Err(_) => {
+                // unwind the calls that were interrupted by the error
+                self.stack.truncate(stack_len);
+                self.frames.truncate(frames_len);
+                self.upvalues.retain(|u| !matches!(*u.borrow(), UpvalI::Open(idx) if idx >= stack_len));
+                None
+            },
+        }
+    }
+
     fn close_upvalue(&mut self, stack_idx: usize, val: Value) {
         if let Some(j) = self.upvalues.iter()
             .position(|x| *x.borrow() == UpvalI::Open(stack_idx))
@@ -493,6 +740,52 @@ impl Vm {
 }
 
 
+// `\(x : Natural) -> x + 1`, passed to the function given to Natural/build.
+fn natural_succ() -> Value {
+    let mut func = Function::new();
+    func.arity = 1;
+    let one = func.chunk.add_constant(Value::Natural(BigUint::one()));
+    for op in [Op::GetVar(1), Op::Constant(one), Op::Add, Op::Return] {
+        func.chunk.push_op(op, 0..0);
+    }
+    Value::Closure(Closure::new(func))
+}
+
+// `\(x : a) -> \(xs : List a) -> [ x ] # xs`, passed to the function given to List/build.
+fn list_cons() -> Value {
+    let mut inner = Function::new();
+    inner.arity = 1;
+    for op in [Op::GetUpval(0), Op::CreateList(1), Op::GetVar(1), Op::ListAppend, Op::Return] {
+        inner.chunk.push_op(op, 0..0);
+    }
+    let mut outer = Function::new();
+    outer.arity = 1;
+    let inner = outer.chunk.add_constant(Value::Function(inner));
+    for op in [Op::Closure(inner), Op::Upval(UpvalueLoc::Local(1)), Op::CloseUpvalue(1), Op::Return] {
+        outer.chunk.push_op(op, 0..0);
+    }
+    Value::Closure(Closure::new(outer))
+}
+
+// Replaces the value at `path` within `val`, keeping everything else. Missing fields are
+// created, while a None stays None.
+fn update(val: Value, path: &[PathComponent], new: Value) -> Result<Value, RuntimeError> {
+    let Some((first, rest)) = path.split_first() else {
+        return Ok(new);
+    };
+    match (first, val) {
+        (PathComponent::Label(k), Value::Record(mut map)) => {
+            let inner = map.remove(k).unwrap_or_else(|| Value::Record(BTreeMap::new()));
+            map.insert(k.clone(), update(inner, rest, new)?);
+            Ok(Value::Record(map))
+        },
+        (PathComponent::DescendOptional, Value::Option(Some(inner))) => Ok(Value::Option(Some(Box::new(update(*inner, rest, new)?)))),
+        (PathComponent::DescendOptional, Value::Option(None)) => Ok(Value::Option(None)),
+        (PathComponent::Label(k), val) => Err(RuntimeError::Basic(format!("Cannot update field {k} of {val:?}, expected a record."))),
+        (PathComponent::DescendOptional, val) => Err(RuntimeError::Basic(format!("Cannot update the content of {val:?}, expected an Optional."))),
+    }
+}
+
 fn combine_record(l: &mut Value, r: &mut Value) -> Result<(), RuntimeError> {
     if let (Value::Record(li), Value::Record(ri)) = (l, r) {
         for (name, val) in ri {
@@ -506,4 +799,123 @@ fn combine_record(l: &mut Value, r: &mut Value) -> Result<(), RuntimeError> {
     } else {
         Err(RuntimeError::Basic("Combine expression can only be usedd with records.".to_string()))
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::eval;
+
+    #[test]
+    fn unions() {
+        assert_eq!(eval("< A | B : Natural >.A").unwrap(), "A");
+        assert_eq!(eval("< A | B : Natural >.B 1").unwrap(), "B 1");
+        assert_eq!(eval("let U = < A | B : Natural > in [ U.B 2, U.A ]").unwrap(), "[ B 2, A ]");
+        let describe = "\\(u : < A | B : Natural >) -> merge { A = 0, B = \\(n : Natural) -> n + 1 } u";
+        assert_eq!(eval(&format!("let f = {describe} in [ f (< A | B : Natural >.B 2), f < A | B : Natural >.A ]")).unwrap(), "[ 3, 0 ]");
+        assert_eq!(eval("merge { Some = \\(n : Natural) -> n, None = 0 } (None Natural)").unwrap(), "0");
+    }
+
+    #[test]
+    fn quoted_union_labels() {
+        assert_eq!(eval("< `a b` | A >.`a b`").unwrap(), "`a b`");
+        assert_eq!(eval("let U = < `if` : Natural | A > in U.`if` 3").unwrap(), "`if` 3");
+        assert_eq!(eval("let U = < `Natural/show` | A > in [ U.`Natural/show`, U.A ]").unwrap(), "[ `Natural/show`, A ]");
+        assert_eq!(eval("< `Some` | `A` >.`A`").unwrap(), "A");
+    }
+
+    #[test]
+    fn if_and_or() {
+        assert_eq!(eval("if True then 1 else 2").unwrap(), "1");
+        assert_eq!(eval("if Natural/even 3 then 1 else 2").unwrap(), "2");
+        assert_eq!(eval("[ True && False, True || False, False == False, True != True ]").unwrap(), "[ False, True, True, False ]");
+        assert_eq!(eval("let f = \\(b : Bool) -> if b || False then \"yes\" else \"no\" in f True ++ f False").unwrap(), "\"yesno\"");
+    }
+
+    #[test]
+    fn fold() {
+        assert_eq!(eval("Natural/fold 3 Natural (\\(n : Natural) -> n * 2) 1").unwrap(), "8");
+        assert_eq!(eval("Natural/fold 3 (List Text) (\\(l : List Text) -> l # [ \"x\" ]) ([] : List Text)").unwrap(), "[ \"x\", \"x\", \"x\" ]");
+        assert_eq!(eval("List/fold Natural [ 1, 2, 3 ] Natural (\\(x : Natural) -> \\(acc : Natural) -> x + acc) 0").unwrap(), "6");
+        assert_eq!(eval("Natural/fold 3 Natural (\\(x : Natural) -> if Natural/isZero x then 5 else x + 1) 0").unwrap(), "7");
+        assert_eq!(eval("Natural/build (\\(N : Type) -> \\(s : N -> N) -> \\(z : N) -> s (s z))").unwrap(), "2");
+        assert_eq!(eval("List/build Natural (\\(L : Type) -> \\(c : Natural -> L -> L) -> \\(n : L) -> c 1 (c 2 n))").unwrap(), "[ 1, 2 ]");
+    }
+
+    #[test]
+    fn partially_applied_builtins() {
+        assert_eq!(eval("let f = Natural/subtract 1 in f 5").unwrap(), "4");
+        assert_eq!(eval("let len = List/length Natural in len [ 1, 2 ]").unwrap(), "2");
+        assert_eq!(eval("Natural/fold 3 Natural").unwrap(), "<function>");
+        assert_eq!(eval("let fold = Natural/fold 3 Natural in fold (\\(x : Natural) -> x * 2) 1").unwrap(), "8");
+        assert_eq!(eval("let len = List/length in len Natural [ 1, 2 ]").unwrap(), "2");
+        // builtins passed as values collect their arguments over separate calls
+        assert_eq!(eval("let g = \\(f : Natural -> Natural -> Natural) -> f 3 10 in g Natural/subtract").unwrap(), "7");
+        assert_eq!(eval("Natural/fold 3 Natural (Natural/subtract 1) 10").unwrap(), "7");
+    }
+
+    #[test]
+    fn fold_successor() {
+        // successors are recognized by applying them once, whatever their bytecode looks like
+        let function = |code: &str| crate::evaluate(code, Path::new("<test>.dhall"), false).unwrap().1;
+        let mut vm = Vm::new();
+        for (code, k) in [
+            ("\\(x : Natural) -> x + 1", Some(1u32)),
+            ("\\(x : Natural) -> let two = 2 in 1 + (x + two)", Some(3)),
+            ("let add = \\(k : Natural) -> \\(x : Natural) -> x + k in add 2", Some(2)),
+            ("\\(x : Natural) -> x", Some(0)),
+            ("\\(x : Natural) -> x * 2", None),
+            ("\\(x : Natural) -> if Natural/isZero x then 1 else x + 1", None),
+            ("\\(x : Natural) -> 5", None),
+            ("Natural/subtract 1", None),
+        ] {
+            assert_eq!(vm.increment(&function(code)), k.map(BigUint::from), "{code}");
+            // failed probes are unwound
+            assert!(vm.stack.is_empty() && vm.frames.is_empty() && vm.upvalues.is_empty(), "{code}");
+        }
+        assert_eq!(eval("Natural/fold 1000000 Natural (\\(x : Natural) -> x + 1) 0").unwrap(), "1000000");
+        assert_eq!(eval("Natural/fold 1000000 Natural (\\(x : Natural) -> let two = 2 in 1 + (x + two)) 1").unwrap(), "3000001");
+    }
+
+    #[test]
+    fn integer_to_double() {
+        assert_eq!(eval("Integer/toDouble -3").unwrap(), "-3.0");
+        assert_eq!(eval("Integer/toDouble +9007199254740993").unwrap(), "9.007199254740992e15");
+        let huge = format!("1{}", "0".repeat(400));
+        assert_eq!(eval(&format!("Integer/toDouble +{huge}")).unwrap(), "Infinity");
+        assert_eq!(eval(&format!("Integer/toDouble -{huge}")).unwrap(), "-Infinity");
+    }
+
+    #[test]
+    fn double_show() {
+        assert_eq!(eval("Double/show 1.0").unwrap(), r#""1.0""#);
+        assert_eq!(eval("Double/show -2.5e-3").unwrap(), r#""-2.5e-3""#);
+        assert_eq!(eval("Double/show 1e7").unwrap(), r#""1.0e7""#);
+        assert_eq!(eval("Double/show -0.0").unwrap(), r#""-0.0""#);
+        assert_eq!(eval("Double/show -Infinity").unwrap(), r#""-Infinity""#);
+    }
+
+    #[test]
+    fn text_show() {
+        assert_eq!(eval(r#"Text/show "a\"b\\c\$""#).unwrap(), r#""\"a\\\"b\\\\c\\u0024\"""#);
+        // other control characters are escaped with lowercase hex digits, as in the standard
+        assert_eq!(eval(r#"Text/show "\u{1B}[0m\n\u{7F}""#).unwrap(), "\"\\\"\\\\u001b[0m\\\\n\u{7F}\\\"\"");
+    }
+
+    #[test]
+    fn to_map() {
+        assert_eq!(eval("toMap { b = 2, a = 1 }").unwrap(), "[ { mapKey = \"a\", mapValue = 1 }, { mapKey = \"b\", mapValue = 2 } ]");
+        assert_eq!(eval("toMap {=} : List { mapKey : Text, mapValue : Bool }").unwrap(), "[]");
+        assert_eq!(eval("let r = { `x.y` = True } in toMap r").unwrap(), "[ { mapKey = \"x.y\", mapValue = True } ]");
+    }
+
+    #[test]
+    fn show_constructor() {
+        assert_eq!(eval("showConstructor (< A | B : Natural >.B 1)").unwrap(), "\"B\"");
+        assert_eq!(eval("showConstructor < A | B : Natural >.A").unwrap(), "\"A\"");
+        assert_eq!(eval("[ showConstructor (Some 1), showConstructor (None Natural) ]").unwrap(), "[ \"Some\", \"None\" ]");
+    }
+}